@@ -0,0 +1,967 @@
+//! Command line argument parsing
+use super::*;
+
+use std::{
+    fmt, error,
+    io::{self, Read, Write}, fs,
+};
+use zeroize::Zeroizing;
+
+/// Where the passphrase a key is derived from comes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Passphrase
+{
+    /// Prompt for it on the terminal, without echoing it
+    Prompt,
+    /// Read it from this file
+    File(String),
+    /// Read it from this file descriptor
+    Fd(i32),
+}
+
+impl Passphrase
+{
+    /// Read the passphrase, with one trailing newline removed.
+    ///
+    /// When prompting for it as `name`, the passphrase must be entered twice if `confirm` is true.
+    fn read(&self, name: &str, confirm: bool) -> Result<Zeroizing<Vec<u8>>, Error>
+    {
+	let mut passphrase = match self {
+	    Self::Prompt => {
+		let prompt = |prompt: &str| rpassword::prompt_password(format!("{}: {}: ", env!("CARGO_PKG_NAME"), prompt)).map(Zeroizing::new).map_err(Error::Passphrase);
+		let passphrase = prompt(name)?;
+		if confirm && prompt(&format!("confirm {}", name))? != passphrase {
+		    return Err(Error::Passphrase(io::Error::new(io::ErrorKind::InvalidInput, "the passphrases do not match")));
+		}
+		Zeroizing::new(passphrase.as_bytes().to_vec())
+	    },
+	    Self::File(path) => Zeroizing::new(fs::read(path).map_err(|e| Error::File(path.clone(), e))?),
+	    &Self::Fd(fd) => read_fd(fd).map_err(Error::Passphrase)?,
+	};
+	if passphrase.last() == Some(&b'\n') {
+	    passphrase.pop();
+	    if passphrase.last() == Some(&b'\r') {
+		passphrase.pop();
+	    }
+	}
+	if passphrase.is_empty() {
+	    return Err(Error::Passphrase(io::Error::new(io::ErrorKind::InvalidInput, "the passphrase is empty")));
+	}
+	Ok(passphrase)
+    }
+}
+
+/// Where the authentication tag of a detached ciphertext goes, or comes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Detached
+{
+    /// Print the tag to stderr in base64 when encrypting
+    Stderr,
+    /// Write the tag to this file when encrypting
+    File(String),
+    /// The tag to check when decrypting
+    Tag(cha::Tag),
+}
+
+/// Where the key comes from, when it is not given on the command line
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum KeyInput
+{
+    /// Read it from this file
+    File(String),
+    /// Read it from this file descriptor
+    Fd(i32),
+    /// The value of the `KEY_ENV` environment variable
+    Env(Zeroizing<Vec<u8>>),
+    /// The key in the keyring with this name or fingerprint
+    Id(String),
+}
+
+/// The environment variable the key is read from, if it is not given any other way
+pub const KEY_ENV: &str = "CHACHA20_KEY";
+
+impl KeyInput
+{
+    /// The option (or variable) the key was given with
+    fn name(&self) -> &'static str
+    {
+	match self {
+	    Self::File(_) => "--key-file",
+	    Self::Fd(_) => "--key-fd",
+	    Self::Env(_) => KEY_ENV,
+	    Self::Id(_) => "--key-id",
+	}
+    }
+
+    /// Read and decode the key (see `Key::decode()`), or find it in `keyring`
+    fn read(&self, keyring: Option<&keyring::Keyring>) -> Result<Key, Error>
+    {
+	let data = match self {
+	    Self::File(path) => Zeroizing::new(fs::read(path).map_err(|e| Error::File(path.clone(), e))?),
+	    &Self::Fd(fd) => read_fd(fd).map_err(|e| Error::File(format!("fd {}", fd), e))?,
+	    Self::Env(value) => value.clone(),
+	    Self::Id(id) => return Ok(keyring.ok_or(Error::Requires("--key-id", "--keyring"))?.find(id).map_err(Error::Keyring)?.key),
+	};
+	Key::decode(&data[..]).map_err(|e| Error::Key(self.name(), e))
+    }
+}
+
+/// Read all of a file descriptor given as an option's value, then close it.
+fn read_fd(fd: i32) -> io::Result<Zeroizing<Vec<u8>>>
+{
+    use std::os::unix::io::FromRawFd;
+    // SAFETY: The fd is not used anywhere else in the program (stdin, stdout, stderr and fds given to more than one option are rejected by `parse_fd()`,) so it is ours to read and close.
+    let mut file = unsafe { fs::File::from_raw_fd(fd) };
+    let mut data = Zeroizing::new(Vec::new());
+    file.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Parse a file descriptor given as an option's value, which may not be stdin (the input), stdout (the output) or stderr, or one of the fds in `used` (which it is added to.)
+///
+/// Each fd is closed once it has been read, so it can only be given to one option.
+fn parse_fd(opt: &'static str, value: String, used: &mut Vec<i32>) -> Result<i32, Error>
+{
+    match value.parse::<i32>() {
+	Ok(fd) if used.contains(&fd) => Err(Error::SharedFd(opt, fd)),
+	Ok(fd) if fd > 2 => {
+	    used.push(fd);
+	    Ok(fd)
+	},
+	_ => Err(Error::Invalid(opt, value)),
+    }
+}
+
+/// Write a key to a new file that only the user can read, in `encoding` as `keygen` prints it.
+///
+/// Fails if the file already exists.
+pub fn write_key_file(path: &str, key: &Key, encoding: key::Encoding) -> io::Result<()>
+{
+    use std::os::unix::fs::OpenOptionsExt;
+    let mut file = fs::OpenOptions::new()
+	.write(true)
+	.create_new(true)
+	.mode(0o600)
+	.open(path)?;
+    writeln!(file, "{}", key.encode(encoding).as_str())?;
+    file.sync_all()
+}
+
+/// What the `keys` mode does to the keyring
+#[derive(Debug, Clone)]
+pub enum KeysCommand
+{
+    /// Print the fingerprint and name of each key
+    List,
+    /// Add this key with this name, then print its fingerprint
+    Add(String, Key),
+    /// Remove the key with this name or fingerprint
+    Remove(String),
+    /// Print the key with this name or fingerprint in this encoding, or write it to this file
+    Export(String, key::Encoding, Option<String>),
+}
+
+/// Options parsed from the command line
+#[derive(Debug)]
+pub struct Options
+{
+    pub mode: Mode,
+    /// The key, or the passphrase it is derived from
+    pub key: cha::KeySource,
+    /// The cipher suite, this is read from the header when decrypting a ciphertext with one
+    pub suite: cha::CipherSuite,
+    /// The IV (or extended nonce,) this is `None` when decrypting a ciphertext with a header, unless one is given.
+    pub nonce: Option<key::Nonce>,
+    /// Whether the fingerprint of the key is recorded in the header (when it is from the keyring)
+    pub key_id: bool,
+    /// Associated data that is authenticated along with the ciphertext, but not encrypted or included in the output
+    pub aad: Vec<u8>,
+    /// How the ciphertext is split up and authenticated
+    pub framing: cha::Framing,
+    /// Whether a header is written before the ciphertext, or read before decrypting it
+    pub header: bool,
+    /// If the tag is kept separately from the ciphertext, where it goes
+    pub detached: Option<Detached>,
+    /// If only a range of the plaintext is decrypted, its offset and length
+    pub range: Option<(usize, Option<usize>)>,
+    /// For `kdf-bench`, the algorithm to calibrate, the time it should take, and the most memory it may use
+    pub calibration: Option<(kdf::Algorithm, std::time::Duration, u64)>,
+    /// For `rewrap`, the file whose header is rewritten, and the changes to its recipients
+    pub rewrap: Option<(String, recipient::Rewrap)>,
+    /// For `keys`, the keyring and what to do to it
+    pub keys: Option<(keyring::Keyring, KeysCommand)>,
+    /// When encrypting, the ledger the key and IV are recorded in, and what to do if they have been used together before
+    pub ledger: Option<(ledger::Ledger, ledger::Policy)>,
+}
+
+impl Options
+{
+    /// The en/decryption these options describe, or `None` if the mode does not process any input.
+    pub fn operation(&self) -> Option<cha::Operation>
+    {
+	let direction = self.mode.direction()?;
+	let op = cha::Operation::with_suite(direction, self.suite, self.key.clone(), self.nonce)
+	    .with_aad(&self.aad[..])
+	    .with_framing(self.framing)
+	    .with_header(self.header)
+	    .with_key_id(self.key_id);
+	Some(match self.detached {
+	    Some(Detached::Tag(tag)) => op.with_tag(tag),
+	    _ => op,
+	})
+    }
+
+    /// Output the tag of a detached encryption to where the options say it goes
+    pub fn write_tag(&self, tag: &cha::Tag) -> io::Result<()>
+    {
+	match &self.detached {
+	    Some(Detached::Stderr) => eprintln!("{}", base64::encode(tag)),
+	    Some(Detached::File(path)) => fs::write(path, tag)?,
+	    Some(Detached::Tag(_)) | None => (),
+	}
+	Ok(())
+    }
+}
+
+/// An error parsing the command line
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error
+{
+    /// The key or IV given on the command line could not be decoded
+    Decode(&'static str, key::KeyParseError),
+    /// An option that takes a value was given without one
+    MissingValue(&'static str),
+    /// An option was given more than once, or alongside another option it conflicts with
+    Conflict(&'static str),
+    /// An option was not recognised
+    Unknown(String),
+    /// More positional arguments were given than expected
+    Extra(String),
+    /// A file given as an option's value could not be read
+    File(String, io::Error),
+    /// An option's value was not valid
+    Invalid(&'static str, String),
+    /// An option was given that cannot be used with another option or mode
+    Incompatible(&'static str, &'static str),
+    /// An option was given without another option it requires
+    Requires(&'static str, &'static str),
+    /// The passphrase could not be read
+    Passphrase(io::Error),
+    /// The key given with this option (or variable) is not a raw, hex or base64 key
+    Key(&'static str, key::KeyParseError),
+    /// A file given as an option's value could not be written
+    Output(String, io::Error),
+    /// `--no-dump` was given, but the process could not be made undumpable
+    NoDump(io::Error),
+    /// The keyring could not be read or changed
+    Keyring(keyring::Error),
+    /// A nonce could not be generated from `--nonce-state`
+    Sequence(sequence::Error),
+    /// A file descriptor was given to more than one option (the second being this one)
+    SharedFd(&'static str, i32),
+}
+
+impl error::Error for Error
+{
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+	Some(match self {
+	    Self::Decode(_, d) | Self::Key(_, d) => d,
+	    Self::Keyring(err) => err,
+	    Self::Sequence(err) => err,
+	    Self::File(_, io) | Self::Passphrase(io) | Self::Output(_, io) | Self::NoDump(io) => io,
+	    _ => return None,
+	})
+    }
+}
+
+impl fmt::Display for Error
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+	match self {
+	    Self::Decode(what, err) => write!(f, "invalid {}: {}", what, err),
+	    Self::MissingValue(opt) => write!(f, "option `{}` requires a value", opt),
+	    Self::Conflict(opt) => write!(f, "option `{}` may only be given once", opt),
+	    Self::Unknown(opt) => write!(f, "unknown option `{}` (see `help`)", opt),
+	    Self::Extra(arg) => write!(f, "unexpected argument `{}` (see `help`)", arg),
+	    Self::File(path, io) => write!(f, "failed to read file `{}`: {}", path, io),
+	    Self::Invalid(opt, value) => write!(f, "invalid value `{}` for option `{}`", value, opt),
+	    Self::Incompatible(opt, with) => write!(f, "option `{}` cannot be used with `{}`", opt, with),
+	    Self::Requires(opt, required) => write!(f, "option `{}` requires `{}`", opt, required),
+	    Self::Passphrase(io) => write!(f, "failed to read the passphrase: {}", io),
+	    Self::Key(opt, err) => write!(f, "the key from `{}` is not a {} byte raw, hex or base64 key: {}", opt, cha::KEY_SIZE, err),
+	    Self::Output(path, io) => write!(f, "failed to write file `{}`: {}", path, io),
+	    Self::NoDump(io) => write!(f, "failed to disable core dumps: {}", io),
+	    Self::Keyring(err) => write!(f, "{}", err),
+	    Self::Sequence(err) => write!(f, "{}", err),
+	    Self::SharedFd(opt, fd) => write!(f, "option `{}`: fd {} has already been given to another option", opt, fd),
+	}
+    }
+}
+
+/// Decode a tag given as base64
+fn parse_tag(opt: &'static str, value: &str) -> Result<cha::Tag, Error>
+{
+    base64::decode(value.trim()).ok()
+	.and_then(|tag| cha::Tag::try_from(&tag[..]).ok())
+	.ok_or_else(|| Error::Invalid(opt, value.to_owned()))
+}
+
+/// Set `opt` to `value`, failing if it has already been set.
+#[inline] fn set_once<T>(opt: &mut Option<T>, name: &'static str, value: T) -> Result<(), Error>
+{
+    if opt.replace(value).is_some() {
+	Err(Error::Conflict(name))
+    } else {
+	Ok(())
+    }
+}
+
+fn usage(prog_name: &str) 
+{
+    eprintln!("{} (v{}) - chacha20_poly1305 command line encryption tool",
+	      env!("CARGO_PKG_NAME"),
+	      env!("CARGO_PKG_VERSION"));
+    eprintln!(" by {} with <3 (licensed GPL v3.0 or later)", env!("CARGO_PKG_AUTHORS"));
+    eprintln!("\nStreams stdin to stdout through a chacha20_poly1305 (or other AEAD) cipher.");
+    eprintln!("The ciphertext starts with a header describing how it was encrypted (including the IV,) so only the key is needed to decrypt it.");
+    eprintln!("The {} byte authentication tag is appended to the ciphertext when encrypting, and checked when decrypting.", cha::TAG_SIZE);
+    eprintln!();
+    eprintln!("Usage: {} encrypt [<options>] [<base64 key>] [<base64 iv>]", prog_name);
+    eprintln!("Usage: {} decrypt [<options>] [<base64 key>] [<base64 iv>]", prog_name);
+    eprintln!("Usage: {} verify [<options>] [<base64 key>] [<base64 iv>]", prog_name);
+    eprintln!("Usage: {} encrypt|decrypt|verify --passphrase [<options>] [<base64 iv>]", prog_name);
+    eprintln!("Usage: {} encrypt|decrypt|verify --key-file <path>|--key-fd <fd> [<options>] [<base64 iv>]", prog_name);
+    eprintln!("Usage: {} encrypt|decrypt|verify --key-id <name|fingerprint> [<options>] [<base64 iv>]", prog_name);
+    eprintln!("Usage: {} encrypt [--recipient <public key>]... [--key-file <path>]... [--passphrase] [--wrap] [<options>] [<base64 iv>]", prog_name);
+    eprintln!("Usage: {} decrypt|verify [--identity <path>]... [--key-file <path>]... [--passphrase] [<options>] [<base64 iv>]", prog_name);
+    eprintln!("Usage: {} rewrap [--identity <path>]... [--key-file <path>]... [--passphrase] [--recipient <public key>]... [--add-key-file <path>]... [--new-passphrase] [--remove-recipient <public key>]... [--remove-key-file <path>]... [--remove-passphrase] <file>", prog_name);
+    eprintln!("Usage: {} keygen [--cipher <suite>] [--format <encoding>] [--out <path>] [<base64 key>] [<base64 iv>]", prog_name);
+    eprintln!("Usage: {} keygen --keypair [--format <encoding>] [--out <path>]", prog_name);
+    eprintln!("Usage: {} keys [--keyring <dir>] list", prog_name);
+    eprintln!("Usage: {} keys [--keyring <dir>] add <name> [--key-file <path>|--key-fd <fd>|<base64 key>]", prog_name);
+    eprintln!("Usage: {} keys [--keyring <dir>] remove <name|fingerprint>", prog_name);
+    eprintln!("Usage: {} keys [--keyring <dir>] export <name|fingerprint> [--format <encoding>] [--out <path>]", prog_name);
+    eprintln!("Usage: {} derive --label <context> [--salt <salt>] [--key-file <path>|--key-fd <fd>|--key-id <name>|<base64 key>] [--format <encoding>] [--out <path>]", prog_name);
+    eprintln!("Usage: {} kdf-bench [--kdf <algorithm>] [--time <ms>] [--memory <MiB>]", prog_name);
+    eprintln!("Usage: {} help", prog_name);
+    eprintln!();
+    eprintln!("(Key size is {}, IV size is {})", cha::KEY_SIZE, cha::IV_SIZE);
+    eprintln!("(requires OpenSSL 1.1.0 or newer)");
+    eprintln!("\nencrypt/decrypt:\n\tIf a key and/or IV are not provided, they are generated randomly and printed to stderr in order on one line each.");
+    eprintln!("\tKeys and IVs are base64 by default, or may be given in another encoding with a `hex:`, `b64:` or `b64url:` prefix. Hex (of the right length) and URL-safe base64 are also detected without a prefix.");
+    eprintln!("\tThe key and IV given must be exactly the cipher's key/IV size once decoded, otherwise they are rejected.");
+    eprintln!("\t--lenient-key\tInstead zero-pad a key or IV argument that is too short, and ignore the extra bytes of one that is too long (the behaviour of earlier versions.) WARNING: a truncated or mistyped key is then accepted, and may be weak.");
+    eprintln!("\tIf the ciphertext fails authentication when decrypting, the program exits with code {}. Plaintext that has already been streamed to stdout must then be discarded.", EXIT_AUTH_FAILURE);
+    eprintln!("\n\t--aad <string>\tAuthenticate this associated data along with the ciphertext. Decryption fails unless the same data is given.");
+    eprintln!("\t--aad-file <path>\tAs `--aad`, but read the associated data from a file.");
+    eprintln!("\t--chunked\tSplit the ciphertext into segments of {} bytes that are each authenticated, so that decryption only ever outputs verified plaintext. This is recorded in the header.", cha::Framing::DEFAULT_SEGMENT_SIZE);
+    eprintln!("\t--segment-size <bytes>\tAs `--chunked`, but with this segment size.");
+    eprintln!("\t--cipher <suite>\tUse this cipher suite: {} (the default,) {} or {}. `auto` picks {} if the CPU has AES instructions, or {} otherwise. This is recorded in the header.",
+	      cha::CipherSuite::ChaCha20Poly1305, cha::CipherSuite::XChaCha20Poly1305, cha::CipherSuite::Aes256Gcm, cha::CipherSuite::Aes256Gcm, cha::CipherSuite::ChaCha20Poly1305);
+    eprintln!("\t--xchacha\tShort for `--cipher xchacha20-poly1305`. XChaCha20-Poly1305 has a {} byte nonce that is safe to generate randomly for any number of messages under one key. The IV given or generated is then the {} byte nonce.", cha::XNONCE_SIZE, cha::XNONCE_SIZE);
+    eprintln!("\t--raw\tDo not write or read a header. The IV (and `--chunked`/`--segment-size` option) must then be given when decrypting.");
+    eprintln!("\t--detached\tDo not append the tag to the ciphertext, print it to stderr in base64 (after any generated key/IV) instead. The ciphertext is then the same size as the plaintext. Implies `--raw`.");
+    eprintln!("\t--tag-file <path>\tAs `--detached`, but write the {} byte tag to this file when encrypting, or read it from this file (raw or base64) when decrypting.", cha::TAG_SIZE);
+    eprintln!("\t--tag <base64 tag>\tDecrypt a detached ciphertext, checking it against this tag. Implies `--raw`.");
+    eprintln!("\t--insecure-no-mac\tUse plain ChaCha20 with no authentication tag, for interoperability with systems that use it. WARNING: this provides NO integrity, the ciphertext can be modified without it being detected. Implies `--raw`.");
+    eprintln!("\t--counter <block>\tWith `--insecure-no-mac`, start at this 32-bit block counter of the keystream (default 0.)");
+    eprintln!("\t--key-file <path>\tRead the key from a file (as {} raw bytes, hex or base64) instead of the command line, where other users of the system can see it. Any positional argument is then the IV.", cha::KEY_SIZE);
+    eprintln!("\t--key-fd <fd>\tAs `--key-file`, but read the key from this open file descriptor.");
+    eprintln!("\t--key-id <name|fingerprint>\tUse the key with this name (or fingerprint, or a prefix of at least {} hex digits of it) in the keyring. When encrypting, the key's fingerprint is recorded in the header.", keyring::MIN_PREFIX);
+    eprintln!("\t(If neither is given and there is no key argument, the key is read from the `{}` environment variable if it is set, as with `--key-file`. Otherwise, a ciphertext with a header is decrypted with the key in the keyring its header records, or with any key in the keyring if it has a file key.)", KEY_ENV);
+    eprintln!("\t--keyring <dir>\tThe keyring directory, instead of `${}` or `$XDG_DATA_HOME/{}/keys` (`~/.local/share/{}/keys` by default.)", keyring::DIR_ENV, env!("CARGO_PKG_NAME"), env!("CARGO_PKG_NAME"));
+    eprintln!("\t--passphrase\tDerive the key from a passphrase instead, prompting for it on the terminal (twice when encrypting.) The key derivation parameters and random salt are recorded in the header, so decrypting only needs the passphrase. Cannot be used with `--raw`.");
+    eprintln!("\t--kdf <params>\tWhen encrypting with a passphrase, derive the key with these parameters, written as `<algorithm>[:<name>=<value>,...]` (as printed by `kdf-bench`.) The algorithms are `{}` (the default, with `m` KiB of memory, `t` passes and `p` lanes) and `{}` (with `log_n`, `r` and `p`.)", kdf::Algorithm::Argon2id, kdf::Algorithm::Scrypt);
+    eprintln!("\t--passphrase-file <path>\tAs `--passphrase`, but read the passphrase from a file (without its trailing newline.)");
+    eprintln!("\t--passphrase-fd <fd>\tAs `--passphrase-file`, but read the passphrase from this open file descriptor.");
+    eprintln!("\t--recipient <public key>\tEncrypt to this X25519 public key (as printed by `keygen --keypair`.) May be given more than once. Cannot be used with `--raw`.");
+    eprintln!("\t--identity <path>\tDecrypt a ciphertext that was encrypted to a public key, with the private key read from this file (as written by `keygen --keypair --out`.) May be given more than once.");
+    eprintln!("\tWhen encrypting to more than one key, passphrase or public key (e.g. `--recipient` twice, or `--key-file` with `--passphrase`,) the ciphertext is encrypted with a random file key, and a copy of it wrapped for each of them is stored in the header. Any one of them can then decrypt it. When decrypting, each key, identity and passphrase given is tried in turn.");
+    eprintln!("\t--wrap\tEncrypt with a wrapped file key even for a single key or passphrase, so that recipients can be added to the header later.");
+    eprintln!("\t--nonce-mode <mode>\tHow the IV is generated when encrypting without one: `random` (the default) or `counter`, which makes it from a random prefix and a counter stored in `--nonce-state`. Random IVs of {} bytes should not be used for more than about 2^32 messages under one key, counter IVs are unique for 2^64 (use `--xchacha` for random IVs that are safe for any number.) Encryption fails, rather than generate an IV, if the counter cannot be read or has run out.", cha::IV_SIZE);
+    eprintln!("\t--nonce-state <path>\tThe file the counter of `--nonce-mode counter` is kept in (created if it does not exist.) Use one file per key; it is locked while the counter is advanced, so processes may share it.");
+    eprintln!("\t--nonce-ledger <dir>\tWhen encrypting with a key (not a passphrase or recipients,) record a hash of the IV in a file for the key in this directory, and refuse to encrypt if the key has been used with the IV before. The file is locked while it is checked, so this is safe for processes encrypting at the same time. This is also enabled by setting `${}` to the directory.", ledger::DIR_ENV);
+    eprintln!("\t--nonce-reuse <policy>\tWhat to do when `--nonce-ledger` finds the IV has been used before: `refuse` (the default) or `warn` (print a warning to stderr, and encrypt anyway.)");
+    eprintln!("\t--no-dump\tBefore reading or generating the key, stop the process from being core dumped or attached to by other unprivileged processes (`PR_SET_DUMPABLE`.) This is always done when built with the `no_dump` feature. Keys and buffers of plaintext are always locked into memory and excluded from core dumps.");
+    eprintln!("\t--offset <bytes>\tWhen decrypting, only output the plaintext from this offset. Only the segments of a chunked ciphertext that the range touches are read (and each is authenticated,) other ciphertexts must still be read (and authenticated) in full.");
+    eprintln!("\t--length <bytes>\tWhen decrypting, only output this many bytes of the plaintext (from `--offset`, or the start.)");
+    eprintln!("\nverify:\n\tCheck the authenticity of stdin as `decrypt` would (with the same options,) but do not write any plaintext. Exits with code 0 if it is authentic, or {} if it is not.", EXIT_AUTH_FAILURE);
+    eprintln!("\nrewrap:\n\tChange who can decrypt the ciphertext in <file> (encrypted with a wrapped file key, see `--wrap`) by rewriting its header in place. The ciphertext itself is not touched or re-encrypted. The file key is unwrapped with the `--identity`, `--key-file` or `--passphrase` options as when decrypting, and `--recipient` adds a public key.");
+    eprintln!("\t--add-key-file <path>\tAdd the key read from this file. May be given more than once.");
+    eprintln!("\t--new-passphrase\tReplace the passphrase (if any) with a new one, prompting for it twice. `--new-passphrase-file` and `--new-passphrase-fd` read it as `--passphrase-file` and `--passphrase-fd` do, and `--kdf` sets its parameters.");
+    eprintln!("\t--remove-recipient <public key>\tRemove this public key. May be given more than once.");
+    eprintln!("\t--remove-key-file <path>\tRemove the key read from this file. May be given more than once.");
+    eprintln!("\t--remove-passphrase\tRemove the passphrase.");
+    eprintln!("\tThe header has {} bytes of room reserved for more recipients. The last recipient cannot be removed. Note that anyone removed who kept a copy of the file key (or of the old file) can still decrypt it.", header::RESERVED_SIZE);
+    eprintln!("\nkeygen:\n\tThe key/iv is printed in the same way as auto-generated keys for the en/decryption modes, but to stdout instead of stderr. If a key is given as parameter, the key is not printed. If the iv is given as a parameter also, nothing is printed.");
+    eprintln!("\t--out <path>\tWrite the key to this new file (which only the user can read) instead of printing it. The IV is still printed.");
+    eprintln!("\t--keypair\tGenerate an X25519 key pair for `--recipient` and `--identity` instead: print the private key, then the public key. With `--out`, the private key is written to the file instead.");
+    eprintln!("\t--format <encoding>\tPrint (or write) the key and IV in this encoding: `{}` (the default,) `{}` or `{}`.", key::Encoding::Base64, key::Encoding::Hex, key::Encoding::Base64Url);
+    eprintln!("\nkeys:\n\tManage the keyring, a directory of named keys (one base64 `<name>.key` file each, readable only by the user.) Keys are referred to by name, or by their fingerprint (a SHA-256 hash of the key, which can be shared) or a prefix of at least {} hex digits of it.", keyring::MIN_PREFIX);
+    eprintln!("\tlist\tPrint the fingerprint and name of each key.");
+    eprintln!("\tadd <name>\tAdd the key given (as with `encrypt`,) or a new random key, then print its fingerprint.");
+    eprintln!("\tremove <name|fingerprint>\tRemove a key.");
+    eprintln!("\texport <name|fingerprint>\tPrint a key (in `--format`,) or write it to a new file with `--out`.");
+    eprintln!("\nderive:\n\tDerive a key from a master key (given as when encrypting, it is not generated) with HKDF-SHA256, and print it in the same way as `keygen` (with `--format` and `--out`.) The same master key, label and salt always derive the same key.");
+    eprintln!("\t--label <context>\tWhat the key is for, e.g. the name of a service. This is the HKDF info, so other HKDF-SHA256 implementations derive the same key.");
+    eprintln!("\t--salt <salt>\tThe HKDF salt (none by default.)");
+    eprintln!("\nkdf-bench:\n\tFind the parameters of a passphrase KDF that take about `--time` milliseconds (default 1000) to derive a key on this machine, using at most `--memory` MiB (default 256.) The parameters are printed to stdout as a `--kdf` option for `encrypt`.");
+    eprintln!("\nhelp:\n\tPrint this message to stderr then exit with code 0");
+}
+
+/// Parse the program's arguments.
+///
+/// If the mode is `help` (or not recognised), the usage is printed and the process exits.
+/// Keys and IVs that are not given are generated and printed.
+pub fn parse() -> Result<Options, Error>
+{
+    let mut args = std::env::args();
+    let prog_name = args.next().unwrap();
+
+    let mode = args.next();
+    let mode = match mode.as_deref() {
+	Some(name) if name.eq_ignore_ascii_case(Mode::KdfBench.name()) => Mode::KdfBench,
+	Some(name) if name.eq_ignore_ascii_case(Mode::Keys.name()) => Mode::Keys,
+	Some(name) if name.eq_ignore_ascii_case(Mode::Derive.name()) => Mode::Derive,
+	name => match name.and_then(|x| x.chars().next().map(|x| x.to_ascii_lowercase())) {
+	    Some('e') => Mode::Encrypt,
+	    Some('d') => Mode::Decrypt,
+	    Some('v') => Mode::Verify,
+	    Some('k') => Mode::Keygen,
+	    Some('r') => Mode::Rewrap,
+	    other => {
+		usage(&prog_name);
+		std::process::exit(if other == Some('h') {0} else {1})
+	    }
+	},
+    };
+
+    let mut positional = Vec::with_capacity(2);
+    let mut aad = None;
+    let mut framing = None;
+    let mut raw = false;
+    let mut suite = None;
+    let mut detached = None;
+    let mut no_mac = false;
+    let mut counter = None;
+    let mut offset = None;
+    let mut length = None;
+    let mut passphrase = None;
+    let mut key_inputs = Vec::new();
+    let mut fds = Vec::new();
+    let mut recipients = Vec::new();
+    let mut identities = Vec::new();
+    let mut wrap = false;
+    let mut add_keys = Vec::new();
+    let mut remove_keys = Vec::new();
+    let mut remove_recipients = Vec::new();
+    let mut new_passphrase = None;
+    let mut remove_passphrase = false;
+    let mut keypair = false;
+    let mut lenient = false;
+    let mut no_dump = cfg!(feature="no_dump");
+    let mut out = None;
+    let mut format = None;
+    let mut kdf = None;
+    let mut keyring = None;
+    let mut nonce_ledger = None;
+    let mut label = None;
+    let mut salt = None;
+    let mut counter_nonces = None;
+    let mut nonce_state = None;
+    let mut nonce_reuse = None;
+    let mut time = None;
+    let mut memory = None;
+
+    let mut options = true;
+    while let Some(arg) = args.next() {
+	if !options || !arg.starts_with('-') || arg.len() < 2 {
+	    positional.push(arg);
+	    continue;
+	} else if arg == "--" {
+	    options = false;
+	    continue;
+	}
+	let (name, mut inline) = match arg.split_once('=') {
+	    Some((name, value)) => (name, Some(value.to_owned())),
+	    None => (&arg[..], None),
+	};
+	macro_rules! value {
+	    ($name:literal) => {
+		match inline.take().or_else(|| args.next()) {
+		    Some(value) => value,
+		    None => return Err(Error::MissingValue($name)),
+		}
+	    };
+	}
+	match name {
+	    "--aad" => set_once(&mut aad, "--aad", value!("--aad").into_bytes())?,
+	    "--aad-file" => {
+		let path = value!("--aad-file");
+		let data = fs::read(&path).map_err(|e| Error::File(path, e))?;
+		set_once(&mut aad, "--aad", data)?
+	    },
+	    "--chunked" => set_once(&mut framing, "--chunked", cha::Framing::Chunked(cha::Framing::DEFAULT_SEGMENT_SIZE))?,
+	    "--segment-size" => {
+		let size = value!("--segment-size");
+		let size = match size.parse::<u32>() {
+		    Ok(0) | Err(_) => return Err(Error::Invalid("--segment-size", size)),
+		    Ok(size) => size as usize,
+		};
+		set_once(&mut framing, "--chunked", cha::Framing::Chunked(size))?
+	    },
+	    "--raw" => raw = true,
+	    "--insecure-no-mac" => no_mac = true,
+	    "--counter" => {
+		let value = value!("--counter");
+		let block = value.parse::<u32>().map_err(|_| Error::Invalid("--counter", value))?;
+		set_once(&mut counter, "--counter", block)?
+	    },
+	    "--offset" => {
+		let value = value!("--offset");
+		let bytes = value.parse::<usize>().map_err(|_| Error::Invalid("--offset", value))?;
+		set_once(&mut offset, "--offset", bytes)?
+	    },
+	    "--length" => {
+		let value = value!("--length");
+		let bytes = value.parse::<usize>().map_err(|_| Error::Invalid("--length", value))?;
+		set_once(&mut length, "--length", bytes)?
+	    },
+	    "--cipher" => {
+		let name = value!("--cipher");
+		let value = match &name[..] {
+		    "auto" => cha::CipherSuite::fastest(),
+		    name => cha::CipherSuite::from_name(name).ok_or_else(|| Error::Invalid("--cipher", name.to_owned()))?,
+		};
+		set_once(&mut suite, "--cipher", value)?
+	    },
+	    "--passphrase" => set_once(&mut passphrase, "--passphrase", Passphrase::Prompt)?,
+	    "--passphrase-file" => set_once(&mut passphrase, "--passphrase", Passphrase::File(value!("--passphrase-file")))?,
+	    "--passphrase-fd" => set_once(&mut passphrase, "--passphrase", Passphrase::Fd(parse_fd("--passphrase-fd", value!("--passphrase-fd"), &mut fds)?))?,
+	    "--key-file" => key_inputs.push(KeyInput::File(value!("--key-file"))),
+	    "--key-fd" => key_inputs.push(KeyInput::Fd(parse_fd("--key-fd", value!("--key-fd"), &mut fds)?)),
+	    "--key-id" => key_inputs.push(KeyInput::Id(value!("--key-id"))),
+	    "--keyring" => set_once(&mut keyring, "--keyring", keyring::Keyring::new(value!("--keyring")))?,
+	    "--nonce-ledger" => set_once(&mut nonce_ledger, "--nonce-ledger", ledger::Ledger::new(value!("--nonce-ledger")))?,
+	    "--label" => set_once(&mut label, "--label", value!("--label"))?,
+	    "--salt" => set_once(&mut salt, "--salt", value!("--salt"))?,
+	    "--nonce-mode" => {
+		let counter = match &value!("--nonce-mode")[..] {
+		    "random" => false,
+		    "counter" => true,
+		    other => return Err(Error::Invalid("--nonce-mode", other.to_owned())),
+		};
+		set_once(&mut counter_nonces, "--nonce-mode", counter)?
+	    },
+	    "--nonce-state" => set_once(&mut nonce_state, "--nonce-state", value!("--nonce-state"))?,
+	    "--nonce-reuse" => {
+		let name = value!("--nonce-reuse");
+		let policy = ledger::Policy::from_name(&name).ok_or_else(|| Error::Invalid("--nonce-reuse", name.clone()))?;
+		set_once(&mut nonce_reuse, "--nonce-reuse", policy)?
+	    },
+	    "--recipient" => {
+		let value = value!("--recipient");
+		recipients.push(value.parse::<recipient::PublicKey>().map_err(|e| Error::Decode("recipient", e))?)
+	    },
+	    "--identity" => identities.push(value!("--identity")),
+	    "--wrap" => wrap = true,
+	    "--add-key-file" => add_keys.push(KeyInput::File(value!("--add-key-file"))),
+	    "--remove-key-file" => remove_keys.push(KeyInput::File(value!("--remove-key-file"))),
+	    "--remove-recipient" => {
+		let value = value!("--remove-recipient");
+		remove_recipients.push(value.parse::<recipient::PublicKey>().map_err(|e| Error::Decode("recipient", e))?)
+	    },
+	    "--new-passphrase" => set_once(&mut new_passphrase, "--new-passphrase", Passphrase::Prompt)?,
+	    "--new-passphrase-file" => set_once(&mut new_passphrase, "--new-passphrase", Passphrase::File(value!("--new-passphrase-file")))?,
+	    "--new-passphrase-fd" => set_once(&mut new_passphrase, "--new-passphrase", Passphrase::Fd(parse_fd("--new-passphrase-fd", value!("--new-passphrase-fd"), &mut fds)?))?,
+	    "--remove-passphrase" => remove_passphrase = true,
+	    "--keypair" => keypair = true,
+	    "--lenient-key" => lenient = true,
+	    "--no-dump" => no_dump = true,
+	    "--out" => set_once(&mut out, "--out", value!("--out"))?,
+	    "--format" => {
+		let name = value!("--format");
+		let encoding = key::Encoding::from_prefix(&name).ok_or_else(|| Error::Invalid("--format", name.clone()))?;
+		set_once(&mut format, "--format", encoding)?
+	    },
+	    "--kdf" => set_once(&mut kdf, "--kdf", value!("--kdf"))?,
+	    "--time" => {
+		let value = value!("--time");
+		let ms = match value.parse::<u64>() {
+		    Ok(0) | Err(_) => return Err(Error::Invalid("--time", value)),
+		    Ok(ms) => ms,
+		};
+		set_once(&mut time, "--time", std::time::Duration::from_millis(ms))?
+	    },
+	    "--memory" => {
+		let value = value!("--memory");
+		let mib = match value.parse::<u64>() {
+		    Ok(mib) if mib > 0 && mib <= kdf::MAX_MEMORY >> 20 => mib,
+		    _ => return Err(Error::Invalid("--memory", value)),
+		};
+		set_once(&mut memory, "--memory", mib << 20)?
+	    },
+	    "--xchacha" => set_once(&mut suite, "--cipher", cha::CipherSuite::XChaCha20Poly1305)?,
+	    "--detached" => set_once(&mut detached, "--detached", Detached::Stderr)?,
+	    "--tag-file" => {
+		let path = value!("--tag-file");
+		let value = match mode.direction() {
+		    Some(cha::Direction::Decrypt) => {
+			let data = fs::read(&path).map_err(|e| Error::File(path.clone(), e))?;
+			match cha::Tag::try_from(&data[..]) {
+			    Ok(tag) => Detached::Tag(tag),
+			    Err(_) => Detached::Tag(parse_tag("--tag-file", &String::from_utf8_lossy(&data))?),
+			}
+		    },
+		    _ => Detached::File(path),
+		};
+		set_once(&mut detached, "--detached", value)?
+	    },
+	    "--tag" => {
+		let tag = value!("--tag");
+		if mode.direction() != Some(cha::Direction::Decrypt) {
+		    return Err(Error::Incompatible("--tag", mode.name()));
+		}
+		set_once(&mut detached, "--detached", Detached::Tag(parse_tag("--tag", &tag)?))?
+	    },
+	    _ => return Err(Error::Unknown(arg)),
+	}
+    }
+    if let Some(detached) = &detached {
+	if mode.direction() == Some(cha::Direction::Decrypt) && detached == &Detached::Stderr {
+	    return Err(Error::MissingValue("--tag"));
+	}
+	if framing.is_some() {
+	    return Err(Error::Incompatible("--detached", "--chunked"));
+	}
+	// The ciphertext is the same size as the plaintext
+	framing = Some(cha::Framing::Detached);
+	raw = true;
+    }
+    let range = (offset.is_some() || length.is_some()).then(|| (offset.unwrap_or(0), length));
+    if range.is_some() && mode != Mode::Decrypt {
+	return Err(Error::Incompatible(if offset.is_some() { "--offset" } else { "--length" }, mode.name()));
+    }
+    let suite = suite.unwrap_or_default();
+    if no_mac {
+	if !suite.has_raw_cipher() {
+	    return Err(Error::Incompatible("--insecure-no-mac", suite.name()));
+	}
+	if mode == Mode::Verify {
+	    return Err(Error::Incompatible("--insecure-no-mac", "verify"));
+	}
+	if detached.is_some() {
+	    return Err(Error::Incompatible("--insecure-no-mac", "--detached"));
+	}
+	if framing.is_some() {
+	    return Err(Error::Incompatible("--insecure-no-mac", "--chunked"));
+	}
+	if aad.is_some() {
+	    return Err(Error::Incompatible("--insecure-no-mac", "--aad"));
+	}
+	if mode != Mode::Keygen {
+	    eprintln!("{}: WARNING: `--insecure-no-mac` provides NO integrity, the ciphertext can be modified without it being detected", env!("CARGO_PKG_NAME"));
+	}
+	framing = Some(cha::Framing::Unauthenticated(counter.unwrap_or(0)));
+	raw = true;
+    } else if counter.is_some() {
+	return Err(Error::Requires("--counter", "--insecure-no-mac"));
+    }
+    let calibration = if mode == Mode::KdfBench {
+	let algorithm = match kdf.take() {
+	    Some(name) => kdf::Algorithm::from_name(&name).ok_or_else(|| Error::Invalid("--kdf", name.clone()))?,
+	    None => kdf::Algorithm::default(),
+	};
+	Some((algorithm, time.unwrap_or(std::time::Duration::from_secs(1)), memory.unwrap_or(256 << 20)))
+    } else if time.is_some() || memory.is_some() {
+	return Err(Error::Incompatible(if time.is_some() { "--time" } else { "--memory" }, mode.name()));
+    } else {
+	None
+    };
+    let kdf = match kdf {
+	// Decrypting reads the KDF from the header
+	Some(_) if mode != Mode::Encrypt && mode != Mode::Rewrap => return Err(Error::Incompatible("--kdf", mode.name())),
+	Some(_) if mode == Mode::Rewrap && new_passphrase.is_none() => return Err(Error::Requires("--kdf", "--new-passphrase")),
+	Some(_) if mode == Mode::Encrypt && passphrase.is_none() => return Err(Error::Requires("--kdf", "--passphrase")),
+	Some(params) => Some(params.parse::<kdf::Kdf>().map_err(|_| Error::Invalid("--kdf", params))?),
+	None => None,
+    };
+    if passphrase.is_some() {
+	if mode.direction().is_none() && mode != Mode::Rewrap {
+	    return Err(Error::Incompatible("--passphrase", mode.name()));
+	}
+	if raw {
+	    // The key derivation parameters are stored in the header
+	    return Err(Error::Incompatible("--passphrase", "--raw"));
+	}
+    }
+    // Recipients can also be added to (and identities unwrap) the header of an existing ciphertext
+    let rewrapping = mode == Mode::Rewrap;
+    for (opt, given, allowed) in [("--recipient", !recipients.is_empty(), mode == Mode::Encrypt || rewrapping),
+				  ("--identity", !identities.is_empty(), mode.direction() == Some(cha::Direction::Decrypt) || rewrapping),
+				  ("--wrap", wrap, mode == Mode::Encrypt)] {
+	if !given {
+	    continue;
+	}
+	if !allowed {
+	    return Err(Error::Incompatible(opt, mode.name()));
+	}
+	if raw {
+	    // The wrapped file key is stored in the header
+	    return Err(Error::Incompatible(opt, "--raw"));
+	}
+    }
+    if keypair && mode != Mode::Keygen {
+	return Err(Error::Incompatible("--keypair", mode.name()));
+    }
+    if !rewrapping {
+	for (opt, given) in [("--add-key-file", !add_keys.is_empty()), ("--remove-key-file", !remove_keys.is_empty()), ("--remove-recipient", !remove_recipients.is_empty()),
+			     ("--new-passphrase", new_passphrase.is_some()), ("--remove-passphrase", remove_passphrase)] {
+	    if given {
+		return Err(Error::Incompatible(opt, mode.name()));
+	    }
+	}
+    } else if raw {
+	return Err(Error::Incompatible("--raw", mode.name()));
+    }
+    if let Some(input) = key_inputs.first() {
+	if mode == Mode::KdfBench {
+	    return Err(Error::Incompatible(input.name(), mode.name()));
+	}
+	// Only a file key can be wrapped for (or unwrapped with) more than one key
+	if key_inputs.len() > 1 && (raw || (mode.direction().is_none() && !rewrapping)) {
+	    return Err(Error::Conflict("--key-file"));
+	}
+    }
+    if mode == Mode::Derive {
+	if label.as_deref().is_none_or(str::is_empty) {
+	    return Err(Error::Requires("derive", "--label"));
+	}
+    } else if label.is_some() {
+	return Err(Error::Incompatible("--label", mode.name()));
+    } else if salt.is_some() {
+	return Err(Error::Incompatible("--salt", mode.name()));
+    }
+    if mode != Mode::Keygen && mode != Mode::Keys && mode != Mode::Derive {
+	if out.is_some() {
+	    return Err(Error::Incompatible("--out", mode.name()));
+	}
+	if format.is_some() {
+	    return Err(Error::Incompatible("--format", mode.name()));
+	}
+    }
+    let format = format.unwrap_or_default();
+    if key_inputs.is_empty() && passphrase.is_none() && identities.is_empty() && (rewrapping || mode == Mode::Derive || (recipients.is_empty() && mode.direction().is_some())) {
+	use std::os::unix::ffi::OsStringExt;
+	key_inputs.extend(std::env::var_os(KEY_ENV).map(|value| KeyInput::Env(Zeroizing::new(value.into_vec()))));
+    }
+    if no_dump {
+	secure::disable_dumps().map_err(Error::NoDump)?;
+    }
+    let keyring = keyring.or_else(keyring::Keyring::from_env);
+    if mode != Mode::Encrypt {
+	if nonce_ledger.is_some() {
+	    return Err(Error::Incompatible("--nonce-ledger", mode.name()));
+	}
+	if nonce_reuse.is_some() {
+	    return Err(Error::Incompatible("--nonce-reuse", mode.name()));
+	}
+	if counter_nonces.is_some() {
+	    return Err(Error::Incompatible("--nonce-mode", mode.name()));
+	}
+    }
+    let mut sequence = match (counter_nonces.unwrap_or(false), nonce_state) {
+	(true, Some(state)) => Some(sequence::NonceSequence::new(state, suite.nonce_size())),
+	(true, None) => return Err(Error::Requires("--nonce-mode", "--nonce-state")),
+	(false, Some(_)) => return Err(Error::Requires("--nonce-state", "--nonce-mode")),
+	(false, None) => None,
+    };
+    let ledger = match (nonce_ledger.or_else(|| (mode == Mode::Encrypt).then(ledger::Ledger::from_env).flatten()), nonce_reuse) {
+	(Some(ledger), policy) => Some((ledger, policy.unwrap_or_default())),
+	(None, Some(_)) => return Err(Error::Requires("--nonce-reuse", "--nonce-ledger")),
+	(None, None) => None,
+    };
+    let key_id = mode == Mode::Encrypt && key_inputs.iter().any(|input| matches!(input, KeyInput::Id(_)));
+    let mut positional = positional.into_iter();
+
+    let keys = if mode == Mode::Keys {
+	let keyring = keyring.clone().ok_or(Error::Requires("keys", "--keyring"))?;
+	let command = positional.next();
+	let mut id = || positional.next().ok_or(Error::Requires("keys", "<name>"));
+	let command = match command.as_deref() {
+	    Some("list") => KeysCommand::List,
+	    Some("add") => {
+		let name = id()?;
+		let key = match (key_inputs.first(), id()) {
+		    (Some(_), Ok(extra)) => return Err(Error::Extra(extra)),
+		    (Some(input), Err(_)) => input.read(Some(&keyring))?,
+		    (None, Ok(key)) => key.parse().map_err(|e| Error::Decode("key", e))?,
+		    (None, Err(_)) => Key::new(),
+		};
+		KeysCommand::Add(name, key)
+	    },
+	    Some("remove") => KeysCommand::Remove(id()?),
+	    Some("export") => KeysCommand::Export(id()?, format, out.clone()),
+	    Some(other) => return Err(Error::Invalid("keys", other.to_owned())),
+	    None => return Err(Error::Requires("keys", "list, add, remove or export")),
+	};
+	if let Some(extra) = positional.next() {
+	    return Err(Error::Extra(extra));
+	}
+	if let (Some(input), false) = (key_inputs.first(), matches!(command, KeysCommand::Add(..))) {
+	    return Err(Error::Requires(input.name(), "keys add"));
+	}
+	// Read above as the key to add, so not to be read (or its fd closed) again below
+	key_inputs.clear();
+	if out.is_some() && !matches!(command, KeysCommand::Export(..)) {
+	    return Err(Error::Requires("--out", "keys export"));
+	}
+	Some((keyring, command))
+    } else {
+	None
+    };
+
+    let rewrap = if rewrapping {
+	let path = positional.next().ok_or(Error::Requires("rewrap", "<file>"))?;
+	let mut changes = recipient::Rewrap::default();
+	for input in &add_keys {
+	    changes.add.push(cha::KeySource::Key(input.read(keyring.as_ref())?));
+	}
+	changes.add.extend(recipients.drain(..).map(cha::KeySource::Recipient));
+	if let Some(new_passphrase) = &new_passphrase {
+	    // The new passphrase replaces the old one
+	    changes.add.push(cha::KeySource::Passphrase(new_passphrase.read("new passphrase", true)?, kdf));
+	    changes.remove_passphrases = true;
+	}
+	changes.remove_passphrases |= remove_passphrase;
+	for input in &remove_keys {
+	    changes.remove.push(cha::KeySource::Key(input.read(keyring.as_ref())?));
+	}
+	changes.remove.extend(remove_recipients.iter().copied().map(cha::KeySource::Recipient));
+	if changes.add.is_empty() && changes.remove.is_empty() && !changes.remove_passphrases {
+	    return Err(Error::Requires("rewrap", "--recipient, --add-key-file, --new-passphrase or a `--remove-` option"));
+	}
+	if key_inputs.is_empty() && passphrase.is_none() && identities.is_empty() {
+	    return Err(Error::Requires("rewrap", "--identity, --key-file or --passphrase"));
+	}
+	Some((path, changes))
+    } else {
+	None
+    };
+    
+    let key = if keypair {
+	let identity = recipient::Identity::new();
+	let public = identity.public_key().expect("x25519 keygen fatal");
+	match &out {
+	    Some(path) => write_key_file(path, identity.expose_secret(), format).map_err(|e| Error::Output(path.clone(), e))?,
+	    None => println!("{}", identity.expose_secret().encode(format).as_str()),
+	}
+	println!("{}", format.encode(public));
+	cha::KeySource::Identity(identity)
+    } else {
+	// The cheapest to try first when decrypting, the passphrase last
+	let mut sources = Vec::with_capacity(key_inputs.len() + identities.len() + recipients.len() + 1);
+	for input in &key_inputs {
+	    sources.push(cha::KeySource::Key(input.read(keyring.as_ref())?));
+	}
+	for path in &identities {
+	    let data = Zeroizing::new(fs::read(path).map_err(|e| Error::File(path.clone(), e))?);
+	    sources.push(cha::KeySource::Identity(recipient::Identity::decode(&data[..]).map_err(|e| Error::Key("--identity", e))?));
+	}
+	if let Some(passphrase) = &passphrase {
+	    sources.push(cha::KeySource::Passphrase(passphrase.read("passphrase", mode == Mode::Encrypt)?, kdf));
+	}
+	sources.extend(recipients.iter().copied().map(cha::KeySource::Recipient));
+	// Without a key, the header of a ciphertext says which key in the keyring (if any) it was encrypted with
+	if let (true, Some(cha::Direction::Decrypt), false, Some(keyring)) = (sources.is_empty() && positional.len() == 0, mode.direction(), raw, &keyring) {
+	    sources.extend(keyring.entries().map_err(Error::Keyring)?.into_iter().map(|entry| cha::KeySource::Key(entry.key)));
+	}
+
+	// Any positional argument is then the IV
+	let key = match sources.len() {
+	    0 => cha::KeySource::Key(match positional.next() {
+		Some(key) if lenient => Key::parse_lenient(&key).map_err(|e| Error::Decode("key", e))?,
+		Some(key) => key.parse().map_err(|e| Error::Decode("key", e))?,
+		None if mode == Mode::KdfBench || mode == Mode::Keys => Key::default(),
+		// Deriving from a random master key would be pointless
+		None if mode == Mode::Derive => return Err(Error::Requires("derive", "--key-file")),
+		None => {
+		    let key = Key::new();
+		    match (mode == Mode::Keygen, &out) {
+			(true, None) => println!("{}", key.encode(format).as_str()),
+			(true, Some(_)) => (),
+			(false, _) => eprintln!("{}", key.encode(key::Encoding::Base64).as_str()),
+		    }
+		    key
+		},
+	    }),
+	    1 => sources.pop().unwrap(),
+	    _ => cha::KeySource::Recipients(sources),
+	};
+	match key {
+	    key if wrap && !key.wraps() => cha::KeySource::Recipients(vec![key]),
+	    key => key,
+	}
+    };
+    if let (Some(path), cha::KeySource::Key(key), Mode::Keygen) = (&out, &key, &mode) {
+	write_key_file(path, key, format).map_err(|e| Error::Output(path.clone(), e))?;
+    }
+    if let (Some(label), cha::KeySource::Key(master)) = (&label, &key) {
+	if let Some(extra) = positional.next() {
+	    return Err(Error::Extra(extra));
+	}
+	let key = master.derive(label.as_bytes(), salt.as_deref().unwrap_or_default().as_bytes());
+	match &out {
+	    Some(path) => write_key_file(path, &key, format).map_err(|e| Error::Output(path.clone(), e))?,
+	    None => println!("{}", key.encode(format).as_str()),
+	}
+    }
+    let counter = sequence.is_some();
+    let mut new_nonce = || match &mut sequence {
+	Some(sequence) => sequence.next().map_err(Error::Sequence),
+	None => Ok(suite.new_nonce()),
+    };
+    let nonce = match positional.next() {
+	// There is no IV
+	Some(extra) if keypair || rewrapping || keys.is_some() || label.is_some() => return Err(Error::Extra(extra)),
+	None if keypair || rewrapping || keys.is_some() || label.is_some() => None,
+	Some(_) if counter => return Err(Error::Incompatible("--nonce-mode", "<iv>")),
+	Some(iv) => Some(suite.parse_nonce(&iv, lenient).map_err(|e| Error::Decode("IV", e))?),
+	// The IV is read from the header
+	None if mode.direction() == Some(cha::Direction::Decrypt) && !raw => None,
+	// Nothing but the passphrase (or one of the recipients' keys) is needed to decrypt it
+	None if passphrase.is_some() || key.wraps() => Some(new_nonce()?),
+	None if mode == Mode::KdfBench => None,
+	None => {
+	    let nonce = new_nonce()?;
+	    if mode == Mode::Keygen {
+		println!("{}", format.encode(nonce));
+	    } else {
+		eprintln!("{}", base64::encode(nonce));
+	    }
+	    Some(nonce)
+	},
+    };
+    if let Some(extra) = positional.next() {
+	return Err(Error::Extra(extra));
+    }
+
+    Ok(Options {
+	mode,
+	key,
+	suite,
+	nonce,
+	key_id,
+	aad: aad.unwrap_or_default(),
+	framing: framing.unwrap_or_default(),
+	header: !raw,
+	detached,
+	range,
+	calibration,
+	rewrap,
+	keys,
+	ledger,
+    })
+}
@@ -5,32 +5,1063 @@ use openssl::{
     },
     error::ErrorStack,
 };
-use crate::key::{Key, IV};
+use std::{
+    fmt, error,
+    io,
+};
+use crate::key::{Key, IV, XNonce, Nonce, KeyParseError};
+use crate::kdf::Kdf;
+use crate::recipient::{self, PublicKey, Identity};
+use crate::header::{
+    self,
+    Header,
+    Section,
+    Error as HeaderError,
+};
+use crate::secure::{Locked, Buffer};
+use zeroize::{Zeroize, Zeroizing};
 
 pub const KEY_SIZE: usize = 32;
 pub const IV_SIZE: usize = 12;
+pub const XNONCE_SIZE: usize = 24;
+pub const TAG_SIZE: usize = 16;
 
-static NEW_CIPHER: fn() -> Cipher = Cipher::chacha20_poly1305;
+/// A Poly1305 authentication tag
+pub type Tag = [u8; TAG_SIZE];
 
-#[inline] pub fn decrypter(key: impl AsRef<Key>, iv: impl AsRef<IV>) -> Result<Crypter, ErrorStack>
+/// An AEAD cipher that ciphertexts can be encrypted with
+///
+/// All suites have a `KEY_SIZE` key and a `TAG_SIZE` tag, and are processed by the same `Operation`s, `stream::Sink`s and containers.
+/// The discriminant is the suite's ID in a `header::Header`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[repr(u8)]
+pub enum CipherSuite
 {
-    Crypter::new(
-	NEW_CIPHER(),
-	Mode::Decrypt,
-	key.as_ref().as_ref(),
+    #[default]
+    ChaCha20Poly1305 = 1,
+    /// ChaCha20-Poly1305 with an extended nonce, see `xchacha20_params()`
+    XChaCha20Poly1305 = 2,
+    Aes256Gcm = 3,
+}
+
+impl CipherSuite
+{
+    /// All suites
+    pub const ALL: [Self; 3] = [Self::ChaCha20Poly1305, Self::XChaCha20Poly1305, Self::Aes256Gcm];
+
+    /// The suite's name, as used by the `--cipher` option
+    #[inline] pub const fn name(self) -> &'static str
+    {
+	match self {
+	    Self::ChaCha20Poly1305 => "chacha20-poly1305",
+	    Self::XChaCha20Poly1305 => "xchacha20-poly1305",
+	    Self::Aes256Gcm => "aes-256-gcm",
+	}
+    }
+
+    /// The suite with this name
+    pub fn from_name(name: &str) -> Option<Self>
+    {
+	Self::ALL.into_iter().find(|suite| suite.name().eq_ignore_ascii_case(name))
+    }
+
+    /// The fastest suite on this host: AES-256-GCM if the CPU has AES and carry-less multiplication instructions, ChaCha20-Poly1305 otherwise.
+    pub fn fastest() -> Self
+    {
+	#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+	if is_x86_feature_detected!("aes") && is_x86_feature_detected!("pclmulqdq") {
+	    return Self::Aes256Gcm;
+	}
+	#[cfg(target_arch = "aarch64")]
+	if std::arch::is_aarch64_feature_detected!("aes") && std::arch::is_aarch64_feature_detected!("pmull") {
+	    return Self::Aes256Gcm;
+	}
+	Self::ChaCha20Poly1305
+    }
+
+    #[inline] pub const fn key_size(self) -> usize
+    {
+	KEY_SIZE
+    }
+
+    #[inline] pub const fn nonce_size(self) -> usize
+    {
+	match self {
+	    Self::ChaCha20Poly1305 | Self::Aes256Gcm => IV_SIZE,
+	    Self::XChaCha20Poly1305 => XNONCE_SIZE,
+	}
+    }
+
+    #[inline] pub const fn tag_size(self) -> usize
+    {
+	TAG_SIZE
+    }
+
+    /// Generate a random nonce for this suite
+    pub fn new_nonce(self) -> Nonce
+    {
+	match self.nonce_size() {
+	    XNONCE_SIZE => Nonce::XNonce(XNonce::new()),
+	    _ => Nonce::IV(IV::new()),
+	}
+    }
+
+    /// Parse a base64 nonce for this suite.
+    ///
+    /// If `lenient`, a nonce of the wrong size is zero-padded or truncated instead of rejected.
+    pub fn parse_nonce(self, s: &str, lenient: bool) -> Result<Nonce, KeyParseError>
+    {
+	Ok(match (self.nonce_size(), lenient) {
+	    (XNONCE_SIZE, false) => Nonce::XNonce(s.parse()?),
+	    (XNONCE_SIZE, true) => Nonce::XNonce(XNonce::parse_lenient(s)?),
+	    (_, false) => Nonce::IV(s.parse()?),
+	    (_, true) => Nonce::IV(IV::parse_lenient(s)?),
+	})
+    }
+
+    /// The OpenSSL cipher this suite's (sub)keys are used with
+    #[inline] fn cipher(self) -> Cipher
+    {
+	match self {
+	    Self::ChaCha20Poly1305 | Self::XChaCha20Poly1305 => Cipher::chacha20_poly1305(),
+	    Self::Aes256Gcm => Cipher::aes_256_gcm(),
+	}
+    }
+
+    /// Is plain (unauthenticated) ChaCha20 available with this suite?
+    #[inline] pub const fn has_raw_cipher(self) -> bool
+    {
+	!matches!(self, Self::Aes256Gcm)
+    }
+}
+
+impl fmt::Display for CipherSuite
+{
+    #[inline] fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+	f.write_str(self.name())
+    }
+}
+
+/// Plain ChaCha20, **without** Poly1305.
+///
+/// # Security
+/// This cipher provides **no integrity** at all: its ciphertexts can be modified (e.g. bits flipped in the plaintext) without it being detected.
+/// It only exists for interoperability with systems that use plain ChaCha20, see `raw_crypter()`.
+static NEW_RAW_CIPHER: fn() -> Cipher = Cipher::chacha20;
+
+/// The size of the IV of `NEW_RAW_CIPHER`: the 32 bit block counter (little endian) followed by the nonce.
+pub const RAW_IV_SIZE: usize = 4 + IV_SIZE;
+
+/// The direction a cipher operation is performed in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction
+{
+    Encrypt,
+    Decrypt,
+}
+
+/// How a ciphertext is split up and authenticated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Framing
+{
+    /// The whole ciphertext is followed by a single authentication tag.
+    ///
+    /// Plaintext cannot be verified until the whole ciphertext has been read.
+    #[default]
+    Single,
+    /// The plaintext is split into segments of this many bytes, each of which is followed by its own authentication tag.
+    ///
+    /// Each segment's nonce is derived from the IV, the segment's index, and whether it is the last segment (the STREAM construction.)
+    /// This lets each segment be verified as soon as it is read, and detects truncation, reordering, and splicing of segments.
+    ///
+    /// The segment size must not be 0.
+    Chunked(usize),
+    /// The ciphertext has a single authentication tag that is not part of it, and is kept separately.
+    ///
+    /// The ciphertext is the same size as the plaintext.
+    Detached,
+    /// The ciphertext is plain ChaCha20 starting at this block counter, with **no authentication tag** at all.
+    ///
+    /// # Security
+    /// This provides **no integrity**: the ciphertext can be modified without it being detected, and decryption never fails.
+    /// It only exists for interoperability with systems that use plain ChaCha20 (see `raw_crypter()`), and cannot have a header.
+    /// Only the ChaCha20 based cipher suites can be used with it, see `CipherSuite::has_raw_cipher()`.
+    Unauthenticated(u32),
+}
+
+impl Framing
+{
+    /// The segment size used by `--chunked` when none is specified
+    pub const DEFAULT_SEGMENT_SIZE: usize = 64 * 1024;
+
+    /// The size of the output of an operation in this direction on `input` bytes.
+    ///
+    /// Encryption adds the authentication tag(s), decryption removes them.
+    /// Returns `None` if the input is not a valid size for a ciphertext when decrypting.
+    pub fn output_size(self, direction: Direction, input: usize) -> Option<usize>
+    {
+	match (self, direction) {
+	    (Self::Single, Direction::Encrypt) => input.checked_add(TAG_SIZE),
+	    (Self::Single, Direction::Decrypt) => input.checked_sub(TAG_SIZE),
+	    (Self::Chunked(size), Direction::Encrypt) => {
+		let segments = std::cmp::max(1, input.div_ceil(size));
+		input.checked_add(segments.checked_mul(TAG_SIZE)?)
+	    },
+	    (Self::Detached | Self::Unauthenticated(_), _) => Some(input),
+	    (Self::Chunked(size), Direction::Decrypt) => {
+		let unit = size.checked_add(TAG_SIZE)?;
+		if (1..TAG_SIZE).contains(&(input % unit)) {
+		    // The last segment is too short to hold a tag
+		    return None;
+		}
+		let segments = std::cmp::max(1, input.div_ceil(unit));
+		input.checked_sub(segments * TAG_SIZE)
+	    },
+	}
+    }
+}
+
+/// The authentication tag of a ciphertext did not match its contents
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct AuthenticationError;
+
+impl error::Error for AuthenticationError{}
+impl fmt::Display for AuthenticationError
+{
+    #[inline] fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+	f.write_str("ciphertext failed authentication")
+    }
+}
+
+impl From<AuthenticationError> for io::Error
+{
+    #[inline] fn from(from: AuthenticationError) -> Self
+    {
+	io::Error::new(io::ErrorKind::InvalidData, from)
+    }
+}
+
+impl AuthenticationError
+{
+    /// Is this I/O error caused by an authentication failure?
+    #[inline] pub fn is_cause_of(error: &io::Error) -> bool
+    {
+	error.get_ref().map(|e| e.is::<Self>()).unwrap_or(false)
+    }
+}
+
+/// Create a decrypter, authenticating `aad` as associated data.
+///
+/// The associated data is not part of the ciphertext, but decryption fails unless the same data was given when encrypting.
+#[inline] pub fn decrypter(key: impl AsRef<Key>, iv: impl AsRef<IV>, aad: impl AsRef<[u8]>) -> Result<Crypter, ErrorStack>
+{
+    crypter(Direction::Decrypt, key, iv, aad)
+}
+/// Create an encrypter, authenticating `aad` as associated data.
+///
+/// The associated data is not part of the ciphertext, but decryption fails unless the same data is given there.
+#[inline] pub fn encrypter(key: impl AsRef<Key>, iv: impl AsRef<IV>, aad: impl AsRef<[u8]>) -> Result<Crypter, ErrorStack>
+{
+    crypter(Direction::Encrypt, key, iv, aad)
+}
+
+/// Create a ChaCha20-Poly1305 crypter for this direction, authenticating `aad` as associated data.
+#[inline] pub fn crypter(direction: Direction, key: impl AsRef<Key>, iv: impl AsRef<IV>, aad: impl AsRef<[u8]>) -> Result<Crypter, ErrorStack>
+{
+    suite_crypter(CipherSuite::ChaCha20Poly1305, direction, key, iv, aad)
+}
+
+/// Create a crypter of this suite for this direction, authenticating `aad` as associated data.
+///
+/// For `CipherSuite::XChaCha20Poly1305`, the key and IV are the ones it reduces to, see `xchacha20_params()`.
+pub fn suite_crypter(suite: CipherSuite, direction: Direction, key: impl AsRef<Key>, iv: impl AsRef<IV>, aad: impl AsRef<[u8]>) -> Result<Crypter, ErrorStack>
+{
+    let mut crypter = Crypter::new(
+	suite.cipher(),
+	match direction {
+	    Direction::Encrypt => Mode::Encrypt,
+	    Direction::Decrypt => Mode::Decrypt,
+	},
+	&key.as_ref().expose_secret()[..],
 	Some(iv.as_ref().as_ref())
-    )
+    )?;
+    let aad = aad.as_ref();
+    if !aad.is_empty() {
+	crypter.aad_update(aad)?;
+    }
+    Ok(crypter)
 }
-#[inline] pub fn encrypter(key: impl AsRef<Key>, iv: impl AsRef<IV>) -> Result<Crypter, ErrorStack>
+
+/// Create a plain ChaCha20 crypter for this direction, starting at block number `counter` of the keystream.
+///
+/// The IV passed to OpenSSL is the counter (little endian) followed by `iv`, see `RAW_IV_SIZE`.
+///
+/// # Security
+/// The ciphertext is **not authenticated**, it can be modified without it being detected. See `NEW_RAW_CIPHER`.
+pub fn raw_crypter(direction: Direction, key: impl AsRef<Key>, iv: impl AsRef<IV>, counter: u32) -> Result<Crypter, ErrorStack>
 {
+    let mut raw_iv = [0u8; RAW_IV_SIZE];
+    raw_iv[..4].copy_from_slice(&counter.to_le_bytes());
+    raw_iv[4..].copy_from_slice(iv.as_ref().as_ref());
     Crypter::new(
-	NEW_CIPHER(),
-	Mode::Encrypt,
-	key.as_ref().as_ref(),
-	Some(iv.as_ref().as_ref())
+	NEW_RAW_CIPHER(),
+	match direction {
+	    Direction::Encrypt => Mode::Encrypt,
+	    Direction::Decrypt => Mode::Decrypt,
+	},
+	&key.as_ref().expose_secret()[..],
+	Some(&raw_iv[..])
     )
 }
 
+/// Finish an encrypting crypter and return its authentication tag.
+pub fn seal(crypter: &mut Crypter) -> Result<Tag, ErrorStack>
+{
+    let mut tag = [0u8; TAG_SIZE];
+    crypter.finalize(&mut [])?;
+    crypter.get_tag(&mut tag[..])?;
+    Ok(tag)
+}
+
+/// Finish a decrypting crypter, checking the data it processed against `tag`.
+pub fn open(crypter: &mut Crypter, tag: &[u8]) -> Result<(), AuthenticationError>
+{
+    if tag.len() != TAG_SIZE {
+	return Err(AuthenticationError);
+    }
+    crypter.set_tag(tag).map_err(|_| AuthenticationError)?;
+    crypter.finalize(&mut []).map_err(|_| AuthenticationError)?;
+    Ok(())
+}
+
+/// Holds back the last `TAG_SIZE` bytes of a ciphertext stream, so the tag can be split off when the stream ends.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TagTrailer
+{
+    buffer: Tag,
+    len: usize,
+}
+
+impl TagTrailer
+{
+    /// Push `input` through the trailer.
+    ///
+    /// `output` is called with each range of bytes that is now known to not be part of the tag, in order.
+    pub fn push<E>(&mut self, input: &[u8], mut output: impl FnMut(&[u8]) -> Result<(), E>) -> Result<(), E>
+    {
+	let total = self.len + input.len();
+	if total <= TAG_SIZE {
+	    self.buffer[self.len..total].copy_from_slice(input);
+	    self.len = total;
+	    return Ok(());
+	}
+	let excess = total - TAG_SIZE;
+
+	// Release the held bytes that are pushed out by the new input
+	let held = std::cmp::min(excess, self.len);
+	if held > 0 {
+	    output(&self.buffer[..held])?;
+	    self.buffer.copy_within(held..self.len, 0);
+	    self.len -= held;
+	}
+	let (release, keep) = input.split_at(excess - held);
+	if !release.is_empty() {
+	    output(release)?;
+	}
+	self.buffer[self.len..].copy_from_slice(keep);
+	self.len = TAG_SIZE;
+	Ok(())
+    }
+
+    /// The tag, if enough bytes have been pushed to contain one.
+    #[inline] pub fn tag(&self) -> Option<&Tag>
+    {
+	if self.len == TAG_SIZE {
+	    Some(&self.buffer)
+	} else {
+	    None
+	}
+    }
+}
+
+/// The HChaCha20 function, used to derive the subkey of XChaCha20 from the key and the first 16 bytes of its nonce.
+///
+/// This is computed with OpenSSL's ChaCha20: the output is the ChaCha20 block function's state after its rounds, but without the initial state added to it.
+/// So the initial state is subtracted back out of words 0..4 and 12..16 of the keystream of a block whose counter and nonce words are `input`.
+pub fn hchacha20(key: &Key, input: &[u8; 16]) -> Result<Key, ErrorStack>
+{
+    const SIGMA: [u32; 4] = [0x61707865, 0x3320646e, 0x79622d32, 0x6b206574];
+    let word = |bytes: &[u8], i: usize| u32::from_le_bytes(bytes[(i * 4)..(i * 4 + 4)].try_into().unwrap());
+
+    let mut crypter = Crypter::new(Cipher::chacha20(), Mode::Encrypt, &key.expose_secret()[..], Some(&input[..]))?;
+    let mut block = [0u8; 64 + 1];
+    crypter.update(&[0u8; 64], &mut block[..])?;
+
+    let mut subkey = Key::default();
+    let output = subkey.expose_secret_mut();
+    for i in 0..4 {
+	output[(i * 4)..(i * 4 + 4)].copy_from_slice(&word(&block, i).wrapping_sub(SIGMA[i]).to_le_bytes());
+	output[(16 + i * 4)..(16 + i * 4 + 4)].copy_from_slice(&word(&block, 12 + i).wrapping_sub(word(input, i)).to_le_bytes());
+    }
+    block.zeroize();
+    Ok(subkey)
+}
+
+/// The ChaCha20-Poly1305 key and IV that an XChaCha20-Poly1305 key and nonce reduce to.
+///
+/// The key is `hchacha20()` of the first 16 bytes of the nonce, and the IV is the last 8 bytes of the nonce after 4 0 bytes.
+pub fn xchacha20_params(key: &Key, nonce: &XNonce) -> Result<(Key, IV), ErrorStack>
+{
+    let nonce: &[u8] = nonce.as_ref();
+    let subkey = hchacha20(key, nonce[..16].try_into().unwrap())?;
+    let mut iv = [0u8; IV_SIZE];
+    iv[4..].copy_from_slice(&nonce[16..]);
+    Ok((subkey, IV::from_bytes(iv)))
+}
+
+/// Derive the nonce of segment number `index` of a chunked ciphertext.
+///
+/// The index is XORed into bytes 3..11 of the IV (big endian), and the last byte is XORed with 1 if this is the last segment.
+pub fn segment_iv(iv: &IV, index: u64, last: bool) -> IV
+{
+    let mut bytes = [0u8; IV_SIZE];
+    bytes.copy_from_slice(iv.as_ref());
+    for (byte, index) in bytes[3..11].iter_mut().zip(index.to_be_bytes()) {
+	*byte ^= index;
+    }
+    bytes[IV_SIZE-1] ^= last as u8;
+    IV::from_bytes(bytes)
+}
+
+/// Where the key of an `Operation` comes from
+#[derive(Clone, PartialEq, Eq)]
+pub enum KeySource
+{
+    /// The key itself
+    Key(Key),
+    /// A passphrase the key is derived from with the `Kdf` in the header.
+    ///
+    /// When encrypting, the key is derived with this `Kdf`, or with `Kdf::default()` (and a new salt for each header) if it is `None`.
+    Passphrase(Zeroizing<Vec<u8>>, Option<Kdf>),
+    /// When encrypting, a public key a new file key is wrapped for (see `recipient`.)
+    Recipient(PublicKey),
+    /// When decrypting, a private key the file key is unwrapped with.
+    Identity(Identity),
+    /// When encrypting, a new file key is wrapped for each of these. When decrypting, each is tried in turn to unwrap the file key.
+    ///
+    /// If a ciphertext has no file key, it is decrypted with the key whose fingerprint its header records, or else with the passphrase among these if it was encrypted with one, or the key otherwise.
+    /// These may not be `Recipients` themselves.
+    Recipients(Vec<KeySource>),
+}
+
+impl KeySource
+{
+    /// Each of `Recipients`, or just this source
+    #[inline] pub fn sources(&self) -> &[KeySource]
+    {
+	match self {
+	    Self::Recipients(sources) => &sources[..],
+	    other => std::slice::from_ref(other),
+	}
+    }
+
+    /// Is a new file key wrapped for this source when encrypting?
+    #[inline] pub fn wraps(&self) -> bool
+    {
+	matches!(self, Self::Recipient(_) | Self::Recipients(_))
+    }
+}
+
+impl fmt::Debug for KeySource
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+	match self {
+	    Self::Key(key) => f.debug_tuple("Key").field(key).finish(),
+	    Self::Passphrase(_, kdf) => f.debug_tuple("Passphrase").field(&format_args!("[REDACTED]")).field(kdf).finish(),
+	    Self::Recipient(public) => f.debug_tuple("Recipient").field(public).finish(),
+	    Self::Identity(identity) => f.debug_tuple("Identity").field(identity).finish(),
+	    Self::Recipients(sources) => f.debug_tuple("Recipients").field(sources).finish(),
+	}
+    }
+}
+
+impl From<Key> for KeySource
+{
+    #[inline] fn from(from: Key) -> Self
+    {
+	Self::Key(from)
+    }
+}
+
+/// The parameters of an en/decryption
+///
+/// # Headers
+/// An operation created `with_header()` reads or writes a `header::Header` before the ciphertext.
+/// Such an operation is resolved into a headerless one with `seal_header()` when encrypting, or `open_header()` once the header has been read when decrypting.
+/// When decrypting, the nonce of the operation may then be `None`, as it is read from the header (along with the cipher suite.)
+/// An operation whose key is a `KeySource::Passphrase`, `Recipient`, `Identity` or `Recipients` must have a header, as the key is derived when it is resolved.
+/// When encrypting to a `KeySource::Recipient` or `Recipients`, the ciphertext is encrypted with a new random file key that is wrapped for each recipient in the header, and a nonce is generated if the operation has none.
+/// When decrypting a ciphertext with a file key, any key source is used to unwrap it.
+///
+/// # Cipher suites
+/// An operation created with `new()` uses ChaCha20-Poly1305 and a 12 byte `IV`, one created with `extended()` uses XChaCha20-Poly1305 and a 24 byte `XNonce`, and `with_suite()` can use any `CipherSuite`.
+/// XChaCha20-Poly1305 reduces to ChaCha20-Poly1305 with a subkey, see `xchacha20_params()`; its nonces are large enough to be chosen randomly for any number of messages under one key.
+#[derive(Debug, Clone)]
+pub struct Operation
+{
+    direction: Direction,
+    framing: Framing,
+    suite: CipherSuite,
+    key: KeySource,
+    nonce: Option<Nonce>,
+    aad: Vec<u8>,
+    header: bool,
+    key_id: bool,
+    tag: Option<Tag>,
+}
+
+impl Operation
+{
+    /// A headerless, single-tag ChaCha20-Poly1305 operation with no associated data
+    #[inline] pub fn new(direction: Direction, key: Key, iv: impl Into<Option<IV>>) -> Self
+    {
+	Self::with_suite(direction, CipherSuite::ChaCha20Poly1305, key, iv.into().map(Nonce::IV))
+    }
+
+    /// A headerless, single-tag XChaCha20-Poly1305 operation with no associated data
+    #[inline] pub fn extended(direction: Direction, key: Key, nonce: impl Into<Option<XNonce>>) -> Self
+    {
+	Self::with_suite(direction, CipherSuite::XChaCha20Poly1305, key, nonce.into().map(Nonce::XNonce))
+    }
+
+    /// A headerless, single-tag operation with no associated data
+    ///
+    /// # Panics
+    /// If the nonce is not the right size for the suite.
+    pub fn with_suite(direction: Direction, suite: CipherSuite, key: impl Into<KeySource>, nonce: Option<Nonce>) -> Self
+    {
+	assert!(nonce.map(|n| n.as_ref().len() == suite.nonce_size()).unwrap_or(true), "nonce is the wrong size for {}", suite);
+	Self {
+	    direction,
+	    framing: Framing::Single,
+	    suite,
+	    key: key.into(),
+	    nonce,
+	    aad: Vec::new(),
+	    header: false,
+	    key_id: false,
+	    tag: None,
+	}
+    }
+
+    /// Authenticate `aad` as associated data
+    #[inline] pub fn with_aad(self, aad: impl Into<Vec<u8>>) -> Self
+    {
+	Self { aad: aad.into(), ..self }
+    }
+    
+    /// Use this framing for the ciphertext
+    #[inline] pub fn with_framing(self, framing: Framing) -> Self
+    {
+	Self { framing, ..self }
+    }
+
+    /// Check a `Framing::Detached` decryption against this tag
+    #[inline] pub fn with_tag(self, tag: Tag) -> Self
+    {
+	Self { tag: Some(tag), ..self }
+    }
+
+    /// Read or write a header before the ciphertext
+    #[inline] pub fn with_header(self, header: bool) -> Self
+    {
+	Self { header, ..self }
+    }
+
+    /// Record the fingerprint of the key in the header, so that the key can be found when decrypting (see `Header::key_id()`.)
+    ///
+    /// This only applies to a `KeySource::Key` that the ciphertext is encrypted with directly.
+    #[inline] pub fn with_key_id(self, key_id: bool) -> Self
+    {
+	Self { key_id, ..self }
+    }
+
+    /// Does the ciphertext have a header that has not been resolved yet?
+    #[inline] pub fn has_header(&self) -> bool
+    {
+	self.header
+    }
+
+    /// The nonce of this operation
+    ///
+    /// # Panics
+    /// If the operation reads its nonce from a header that has not been opened yet.
+    #[inline] fn nonce(&self) -> &Nonce
+    {
+	self.nonce.as_ref().expect("the nonce of this operation is read from the header, which has not been opened")
+    }
+
+    /// The key of this operation
+    ///
+    /// # Panics
+    /// If the key is derived from a passphrase or public key, and the header has not been resolved yet.
+    #[inline] fn key(&self) -> &Key
+    {
+	match &self.key {
+	    KeySource::Key(key) => key,
+	    _ => panic!("the key of this operation is derived from the header, which has not been resolved"),
+	}
+    }
+
+    /// The key and IV this operation's crypters are created with
+    ///
+    /// # Panics
+    /// If the operation reads its nonce from a header that has not been opened yet, or derives its key from a header that has not been resolved.
+    fn params(&self) -> Result<(Key, IV), ErrorStack>
+    {
+	match (self.suite, self.nonce()) {
+	    (CipherSuite::ChaCha20Poly1305 | CipherSuite::Aes256Gcm, &Nonce::IV(iv)) => Ok((self.key().clone(), iv)),
+	    (CipherSuite::XChaCha20Poly1305, Nonce::XNonce(nonce)) => xchacha20_params(self.key(), nonce),
+	    (suite, _) => unreachable!("nonce is the wrong size for {}", suite),
+	}
+    }
+
+    /// Create the header for this encryption.
+    ///
+    /// Returns the header, and the headerless operation that encrypts the ciphertext following it (which authenticates the header as associated data.)
+    pub fn seal_header(&self) -> Result<(Header, Self), HeaderError>
+    {
+	let (header, key) = self.new_header()?;
+	let op = self.resolve(&header, key)?;
+	Ok((header, op))
+    }
+
+    /// The header for this encryption, without resolving the operation (which may derive its key from a passphrase.)
+    ///
+    /// Also returns the key, if it was derived while creating the header.
+    fn new_header(&self) -> Result<(Header, Option<Key>), HeaderError>
+    {
+	debug_assert_eq!(self.direction, Direction::Encrypt);
+	if self.key.wraps() {
+	    let (key, stanzas) = recipient::seal(self.key.sources())?;
+	    // The file key is only ever used for this ciphertext, so any nonce is safe
+	    let mut header = Header::new(self.suite, self.nonce.unwrap_or_else(|| self.suite.new_nonce()), self.framing)?;
+	    header.sections.extend(stanzas.into_iter().map(|stanza| Section::Recipient(stanza.to_bytes())));
+	    // Leave room for more recipients to be added in place
+	    header.sections.push(Section::Padding(header::RESERVED_SIZE));
+	    return Ok((header, Some(key)));
+	}
+	let mut header = Header::new(self.suite, *self.nonce(), self.framing)?;
+	match &self.key {
+	    KeySource::Passphrase(_, kdf) => header.sections.push(Section::Kdf(kdf.unwrap_or_default().to_bytes())),
+	    KeySource::Key(key) if self.key_id => header.sections.push(Section::KeyId(key.fingerprint())),
+	    _ => (),
+	}
+	Ok((header, None))
+    }
+
+    /// The size of the header `new_header()` creates, without deriving or wrapping any keys.
+    fn header_size(&self) -> Result<usize, HeaderError>
+    {
+	if !self.key.wraps() {
+	    return Ok(self.new_header()?.0.to_bytes()?.len());
+	}
+	let mut header = Header::new(self.suite, self.nonce.unwrap_or_else(|| self.suite.new_nonce()), self.framing)?;
+	header.sections.push(Section::Padding(header::RESERVED_SIZE));
+	let stanzas: usize = self.key.sources().iter().map(|source| 3 + recipient::Stanza::size_for(source)).sum();
+	Ok(header.to_bytes()?.len() + stanzas)
+    }
+
+    /// Resolve this decryption with the header read from the ciphertext.
+    ///
+    /// Returns the headerless operation that decrypts the ciphertext following the header.
+    /// If this operation was given a nonce or framing, they (and the cipher suite) must match the header's.
+    /// Otherwise, the cipher suite is also read from the header.
+    pub fn open_header(&self, header: &Header) -> Result<Self, HeaderError>
+    {
+	self.apply_header(header)?.resolve(header, None)
+    }
+
+    /// Take the cipher suite, nonce and framing of this decryption from `header`, without resolving the operation (which may derive its key.)
+    fn apply_header(&self, header: &Header) -> Result<Self, HeaderError>
+    {
+	debug_assert_eq!(self.direction, Direction::Decrypt);
+	if self.nonce.is_some() && (self.nonce != Some(header.nonce) || self.suite != header.suite) {
+	    return Err(HeaderError::NonceMismatch);
+	}
+	let framing = header.framing()?;
+	if self.framing != Framing::Single && self.framing != framing {
+	    return Err(HeaderError::FramingMismatch);
+	}
+	Ok(Self {
+	    suite: header.suite,
+	    nonce: Some(header.nonce),
+	    framing,
+	    ..self.clone()
+	})
+    }
+
+    /// The headerless operation that processes the ciphertext following `header` with `key`, or the key this operation derives from the header.
+    fn resolve(&self, header: &Header, key: Option<Key>) -> Result<Self, HeaderError>
+    {
+	let mut aad = header.aad()?;
+	aad.extend_from_slice(&self.aad[..]);
+	let key = match (key, &self.key) {
+	    (Some(key), _) => key,
+	    (None, source) => match &header.recipients()?[..] {
+		[] => Self::header_key(source, header)?,
+		stanzas => recipient::open(source.sources(), stanzas)?,
+	    },
+	};
+	Ok(Self {
+	    key: KeySource::Key(key),
+	    nonce: Some(header.nonce),
+	    aad,
+	    header: false,
+	    ..self.clone()
+	})
+    }
+
+    /// The key `source` decrypts a ciphertext with no file key with, as described by `header`.
+    fn header_key(source: &KeySource, header: &Header) -> Result<Key, HeaderError>
+    {
+	match source {
+	    KeySource::Key(key) if header.key_id().is_some_and(|id| id != key.fingerprint()) => Err(HeaderError::NotRecipient),
+	    KeySource::Key(key) => Ok(key.clone()),
+	    KeySource::Passphrase(passphrase, _) => Ok(header.kdf()?
+		.ok_or(HeaderError::MissingKdf)?
+		.derive(&passphrase[..])?),
+	    KeySource::Recipient(_) | KeySource::Identity(_) => Err(HeaderError::MissingRecipient),
+	    KeySource::Recipients(sources) if header.key_id().is_some() => sources.iter()
+		.find(|source| matches!(source, KeySource::Key(key) if Some(key.fingerprint()) == header.key_id()))
+		.map_or(Err(HeaderError::NotRecipient), |source| Self::header_key(source, header)),
+	    KeySource::Recipients(sources) => {
+		let passphrase = header.kdf()?.is_some();
+		sources.iter()
+		    .find(|source| matches!((source, passphrase), (KeySource::Key(_), false) | (KeySource::Passphrase(..), true)))
+		    .or(sources.first())
+		    .map_or(Err(HeaderError::MissingRecipient), |source| Self::header_key(source, header))
+	    },
+	}
+    }
+
+    #[inline] pub fn direction(&self) -> Direction
+    {
+	self.direction
+    }
+
+    #[inline] pub fn framing(&self) -> Framing
+    {
+	self.framing
+    }
+
+    #[inline] pub fn suite(&self) -> CipherSuite
+    {
+	self.suite
+    }
+
+    /// The tag a detached decryption is checked against, if one was given
+    #[inline] pub fn tag(&self) -> Option<&Tag>
+    {
+	self.tag.as_ref()
+    }
+
+    /// Check a detached decryption's crypter against the tag given with `with_tag()`
+    pub fn open_detached(&self, crypter: &mut Crypter) -> Result<(), AuthenticationError>
+    {
+	open(crypter, &self.tag.ok_or(AuthenticationError)?[..])
+    }
+
+    /// The size of the output of this operation on `input` bytes, see `Framing::output_size()`.
+    ///
+    /// Returns `None` for a decryption with a header, as the header's size is not known, use `output_size_of()` instead.
+    pub fn output_size(&self, input: usize) -> Option<usize>
+    {
+	match (self.header, self.direction) {
+	    (false, _) => self.framing.output_size(self.direction, input),
+	    (true, Direction::Encrypt) => {
+		self.framing.output_size(self.direction, input)?.checked_add(self.header_size().ok()?)
+	    },
+	    (true, Direction::Decrypt) => None,
+	}
+    }
+
+    /// The size of the output of this operation on `input`.
+    ///
+    /// Unlike `output_size()`, this can read the header of the input when decrypting.
+    pub fn output_size_of(&self, input: &[u8]) -> Option<usize>
+    {
+	match (self.header, self.direction) {
+	    (true, Direction::Decrypt) => {
+		let (header, len) = Header::parse(input).ok()??;
+		let op = self.apply_header(&header).ok()?;
+		op.framing.output_size(op.direction, input.len() - len)
+	    },
+	    _ => self.output_size(input.len()),
+	}
+    }
+
+    /// Create the crypter for a single-tag ciphertext
+    ///
+    /// # Panics
+    /// If the operation has a header that has not been resolved, or is `Framing::Unauthenticated` with a suite that is not ChaCha20 based.
+    #[inline] pub fn crypter(&self) -> Result<Crypter, ErrorStack>
+    {
+	debug_assert!(!self.header, "crypter() called before the header was resolved");
+	let (key, iv) = self.params()?;
+	match self.framing {
+	    Framing::Unauthenticated(counter) => {
+		assert!(self.suite.has_raw_cipher(), "plain ChaCha20 is not available with {}", self.suite);
+		raw_crypter(self.direction, key, iv, counter)
+	    },
+	    _ => suite_crypter(self.suite, self.direction, key, iv, &self.aad[..]),
+	}
+    }
+    
+    /// Create the crypter for segment number `index` of a chunked ciphertext
+    ///
+    /// # Panics
+    /// If the operation has a header that has not been resolved.
+    #[inline] pub fn segment_crypter(&self, index: u64, last: bool) -> Result<Crypter, ErrorStack>
+    {
+	debug_assert!(!self.header, "segment_crypter() called before the header was resolved");
+	let (key, iv) = self.params()?;
+	suite_crypter(self.suite, self.direction, key, segment_iv(&iv, index, last), &self.aad[..])
+    }
+
+    /// Transform segment number `index` of a chunked ciphertext from `input` into `output`, returning the number of bytes written.
+    ///
+    /// When encrypting, `output` must have room for `input` and the tag.
+    /// When decrypting, `input` ends with the segment's tag; nothing is considered written unless it is verified.
+    pub fn segment(&self, index: u64, last: bool, input: &[u8], output: &mut [u8]) -> io::Result<usize>
+    {
+	let mut crypter = self.segment_crypter(index, last)?;
+	match self.direction {
+	    Direction::Encrypt => {
+		let n = crypter.update(input, &mut output[..input.len()])?;
+		output[n..(n + TAG_SIZE)].copy_from_slice(&seal(&mut crypter)?[..]);
+		Ok(n + TAG_SIZE)
+	    },
+	    Direction::Decrypt => {
+		let len = input.len().checked_sub(TAG_SIZE).ok_or(AuthenticationError)?;
+		let (input, tag) = input.split_at(len);
+		let n = crypter.update(input, &mut output[..len])?;
+		open(&mut crypter, tag)?;
+		Ok(n)
+	    },
+	}
+    }
+
+    /// Transform the whole of `input` into `output`, returning the number of bytes written to `output`.
+    ///
+    /// `output` must be at least `output_size_of(input)` bytes long.
+    /// If decryption fails authentication, unverified plaintext may have been written to `output`.
+    ///
+    /// The tag of a `Framing::Detached` encryption is discarded, use `process_with_tag()` to get it.
+    #[inline] pub fn process(&self, input: &[u8], output: &mut [u8]) -> io::Result<usize>
+    {
+	self.process_with_tag(input, output).map(|(len, _)| len)
+    }
+
+    /// As `process()`, but also return the tag if this is a `Framing::Detached` encryption.
+    pub fn process_with_tag(&self, input: &[u8], output: &mut [u8]) -> io::Result<(usize, Option<Tag>)>
+    {
+	if self.header {
+	    return match self.direction {
+		Direction::Encrypt => {
+		    let (header, op) = self.seal_header()?;
+		    let header = header.to_bytes()?;
+		    if output.len() < header.len() {
+			return Err(io::Error::new(io::ErrorKind::InvalidInput, "output buffer is too small for the header"));
+		    }
+		    output[..header.len()].copy_from_slice(&header[..]);
+		    let (len, tag) = op.process_with_tag(input, &mut output[header.len()..])?;
+		    Ok((header.len() + len, tag))
+		},
+		Direction::Decrypt => {
+		    let (header, len) = Header::parse(input)?.ok_or(HeaderError::Truncated)?;
+		    self.open_header(&header)?.process_with_tag(&input[len..], output)
+		},
+	    };
+	}
+	let len = self.output_size(input.len()).ok_or(AuthenticationError)?;
+	if output.len() < len {
+	    return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("output buffer is too small ({} < {})", output.len(), len)));
+	}
+	let mut detached = None;
+	match (self.framing, self.direction) {
+	    (Framing::Unauthenticated(_), _) => {
+		self.crypter()?.update(input, &mut output[..len])?;
+	    },
+	    (Framing::Detached, Direction::Encrypt) => {
+		let mut crypter = self.crypter()?;
+		crypter.update(input, &mut output[..len])?;
+		detached = Some(seal(&mut crypter)?);
+	    },
+	    (Framing::Detached, Direction::Decrypt) => {
+		let mut crypter = self.crypter()?;
+		crypter.update(input, &mut output[..len])?;
+		self.open_detached(&mut crypter)?;
+	    },
+	    (Framing::Single, Direction::Encrypt) => {
+		let mut crypter = self.crypter()?;
+		let n = crypter.update(input, &mut output[..input.len()])?;
+		output[n..(n + TAG_SIZE)].copy_from_slice(&seal(&mut crypter)?[..]);
+	    },
+	    (Framing::Single, Direction::Decrypt) => {
+		let mut crypter = self.crypter()?;
+		let (input, tag) = input.split_at(len);
+		crypter.update(input, &mut output[..len])?;
+		open(&mut crypter, tag)?;
+	    },
+	    (Framing::Chunked(size), direction) => {
+		let size = match direction {
+		    Direction::Encrypt => size,
+		    Direction::Decrypt => size + TAG_SIZE,
+		};
+		let segments = std::cmp::max(1, input.len().div_ceil(size));
+		let mut written = 0;
+		for index in 0..segments {
+		    let start = index * size;
+		    let segment = &input[start..std::cmp::min(start + size, input.len())];
+		    written += self.segment(index as u64, index + 1 == segments, segment, &mut output[written..])?;
+		}
+		debug_assert_eq!(written, len);
+	    },
+	}
+	Ok((len, detached))
+    }
+}
+
+impl Operation
+{
+    /// The size of the buffer `verify()` decrypts into
+    const VERIFY_BUFFER_SIZE: usize = 64 * 1024;
+
+    /// Check the authenticity of the ciphertext `input` without producing its plaintext.
+    ///
+    /// The ciphertext is decrypted a piece at a time into a small locked scratch buffer, which is zeroed out when it is dropped.
+    pub fn verify(&self, input: &[u8]) -> io::Result<()>
+    {
+	debug_assert_eq!(self.direction, Direction::Decrypt);
+	if self.header {
+	    let (header, len) = Header::parse(input)?.ok_or(HeaderError::Truncated)?;
+	    return self.open_header(&header)?.verify(&input[len..]);
+	}
+	let mut scratch = Locked::new(vec![0u8; match self.framing {
+	    Framing::Chunked(size) => size + TAG_SIZE,
+	    _ => Self::VERIFY_BUFFER_SIZE,
+	}]);
+	self.verify_into(input, &mut scratch[..])
+    }
+
+    fn verify_into(&self, input: &[u8], scratch: &mut [u8]) -> io::Result<()>
+    {
+	let len = self.output_size(input.len()).ok_or(AuthenticationError)?;
+	match self.framing {
+	    Framing::Unauthenticated(_) => return Err(io::Error::new(io::ErrorKind::InvalidInput, "an unauthenticated ciphertext cannot be verified")),
+	    Framing::Single | Framing::Detached => {
+		let mut crypter = self.crypter()?;
+		for piece in input[..len].chunks(scratch.len()) {
+		    crypter.update(piece, &mut scratch[..piece.len()])?;
+		}
+		match self.framing {
+		    Framing::Detached => self.open_detached(&mut crypter)?,
+		    _ => open(&mut crypter, &input[len..])?,
+		}
+	    },
+	    Framing::Chunked(size) => {
+		let size = size + TAG_SIZE;
+		let segments = std::cmp::max(1, input.len().div_ceil(size));
+		for index in 0..segments {
+		    let start = index * size;
+		    let segment = &input[start..std::cmp::min(start + size, input.len())];
+		    self.segment(index as u64, index + 1 == segments, segment, scratch)?;
+		}
+	    },
+	}
+	Ok(())
+    }
+
+    /// Decrypt only the plaintext bytes `offset..(offset + length)` of the ciphertext `input` (or up to its end, if `length` is `None`,) writing them to `output`.
+    ///
+    /// Returns the number of bytes written, which is less than `length` if the range goes past the end of the plaintext.
+    ///
+    /// How much of `input` is read depends on the framing:
+    /// * `Chunked` - Only the segments the range touches are read, and each of them is authenticated before any of it is written.
+    /// * `Unauthenticated` - Only the range is read, the keystream is started at the block the range starts in. **Nothing is verified.**
+    /// * `Single` and `Detached` - The whole ciphertext must be read to authenticate it. The range is held in memory and only written once it has been.
+    pub fn process_range(&self, input: &[u8], offset: usize, length: Option<usize>, mut output: impl io::Write) -> io::Result<usize>
+    {
+	const BLOCK_SIZE: usize = 64;
+	
+	debug_assert_eq!(self.direction, Direction::Decrypt);
+	if self.header {
+	    let (header, len) = Header::parse(input)?.ok_or(HeaderError::Truncated)?;
+	    return self.open_header(&header)?.process_range(&input[len..], offset, length, output);
+	}
+	let total = self.output_size(input.len()).ok_or(AuthenticationError)?;
+	let start = std::cmp::min(offset, total);
+	let end = match length {
+	    Some(length) => std::cmp::min(start.saturating_add(length), total),
+	    None => total,
+	};
+	match self.framing {
+	    Framing::Unauthenticated(counter) => {
+		let (key, iv) = self.params()?;
+		let block = u32::try_from(start / BLOCK_SIZE).ok()
+		    .and_then(|block| counter.checked_add(block))
+		    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "offset is past the end of the keystream"))?;
+		assert!(self.suite.has_raw_cipher(), "plain ChaCha20 is not available with {}", self.suite);
+		let mut crypter = raw_crypter(self.direction, key, iv, block)?;
+		let mut buffer = Locked::new(vec![0u8; Self::VERIFY_BUFFER_SIZE]);
+		let mut position = start - (start % BLOCK_SIZE);
+		while position < end {
+		    let n = std::cmp::min(buffer.len(), end - position);
+		    crypter.update(&input[position..(position + n)], &mut buffer[..n])?;
+		    output.write_all(&buffer[(start.saturating_sub(position))..n])?;
+		    position += n;
+		}
+	    },
+	    Framing::Single | Framing::Detached => {
+		let mut crypter = self.crypter()?;
+		// Both are zeroed out when they are dropped, including when authentication fails
+		let mut range = Buffer::with_capacity(end - start);
+		let mut buffer = Locked::new(vec![0u8; Self::VERIFY_BUFFER_SIZE]);
+		for (index, piece) in input[..total].chunks(buffer.len()).enumerate() {
+		    let position = index * buffer.len();
+		    crypter.update(piece, &mut buffer[..piece.len()])?;
+		    let from = start.clamp(position, position + piece.len());
+		    let to = end.clamp(position, position + piece.len());
+		    range.extend_from_slice(&buffer[(from - position)..(to - position)]);
+		}
+		match self.framing {
+		    Framing::Detached => self.open_detached(&mut crypter)?,
+		    _ => open(&mut crypter, &input[total..])?,
+		}
+		output.write_all(&range[..])?;
+	    },
+	    Framing::Chunked(size) if start < end => {
+		let unit = size + TAG_SIZE;
+		let segments = std::cmp::max(1, input.len().div_ceil(unit));
+		let mut buffer = Locked::new(vec![0u8; unit]);
+		for index in (start / size)..=((end - 1) / size) {
+		    let from = index * unit;
+		    let segment = &input[from..std::cmp::min(from + unit, input.len())];
+		    let n = self.segment(index as u64, index + 1 == segments, segment, &mut buffer[..])?;
+		    let position = index * size;
+		    output.write_all(&buffer[(std::cmp::max(start, position) - position)..(std::cmp::min(end, position + n) - position)])?;
+		}
+	    },
+	    Framing::Chunked(_) => (),
+	}
+	Ok(end - start)
+    }
+}
+
 /// Generate a random key and IV.
 #[inline(always)] pub fn keygen() -> (Key, IV)
 {
@@ -120,6 +120,78 @@ impl<I: Iterator<Item = u8> + Clone> fmt::Display for HexStringIter<I>
     }
 }
 
+/// Decodes pairs of hex digits into bytes, the inverse of `HexStringIter`.
+///
+/// Upper and lower case digits are both accepted.
+#[derive(Debug, Clone)]
+pub struct HexDecodeIter<I>(I, usize);
+
+/// An error decoding hex
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HexDecodeError
+{
+    /// The character at this index is not a hex digit
+    InvalidDigit(char, usize),
+    /// The input ended in the middle of a byte
+    OddLength,
+}
+
+impl std::error::Error for HexDecodeError{}
+impl fmt::Display for HexDecodeError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+	match self {
+	    Self::InvalidDigit(c, i) => write!(f, "invalid hex digit {:?} at offset {}", c, i),
+	    Self::OddLength => f.write_str("odd number of hex digits"),
+	}
+    }
+}
+
+pub trait HexDecodeIterExt<I>: Sized
+{
+    fn unhex(self) -> HexDecodeIter<I>;
+}
+
+impl<I: IntoIterator<Item = char>> HexDecodeIterExt<I::IntoIter> for I
+{
+    #[inline] fn unhex(self) -> HexDecodeIter<I::IntoIter> {
+	HexDecodeIter(self.into_iter(), 0)
+    }
+}
+
+impl<I: Iterator<Item = char>> Iterator for HexDecodeIter<I>
+{
+    type Item = Result<u8, HexDecodeError>;
+    fn next(&mut self) -> Option<Self::Item>
+    {
+	let digit = |this: &mut Self, c: char| {
+	    let index = this.1;
+	    this.1 += 1;
+	    c.to_digit(16).map(|d| d as u8).ok_or(HexDecodeError::InvalidDigit(c, index))
+	};
+	let high = self.0.next()?;
+	let Some(low) = self.0.next() else {
+	    return Some(Err(HexDecodeError::OddLength));
+	};
+	Some(digit(self, high).and_then(|high| Ok((high << 4) | digit(self, low)?)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+	let (l, h) = self.0.size_hint();
+
+	(l / 2, h.map(|x| x.div_ceil(2)))
+    }
+}
+
+impl<I: Iterator<Item = char> + FusedIterator> FusedIterator for HexDecodeIter<I>{}
+
+/// Decode a string of hex digits, the inverse of `HexStringSliceIterExt::hex()`
+#[inline] pub fn decode_hex(s: &str) -> Result<Vec<u8>, HexDecodeError>
+{
+    s.chars().unhex().collect()
+}
+
 #[macro_export] macro_rules! prog1 {
     ($first:expr, $($rest:expr);+ $(;)?) => {
 	($first, $( $rest ),+).0
@@ -147,3 +219,56 @@ pub fn explicit_prune(buffer : &mut[u8]) {
 	}
     }
 }
+
+/// A directory for a test to create files in, which is removed (with everything in it) when dropped, even if the test fails
+#[cfg(test)]
+#[derive(Debug)]
+pub struct TempDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TempDir
+{
+    /// A new, empty directory for the test `name`
+    pub fn new(name: &str) -> Self
+    {
+	let path = std::env::temp_dir().join(format!("chacha20-{}-test-{}", name, std::process::id()));
+	let _ = std::fs::remove_dir_all(&path);
+	std::fs::create_dir_all(&path).expect("failed to create temporary directory");
+	Self(path)
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TempDir
+{
+    type Target = std::path::Path;
+    #[inline] fn deref(&self) -> &Self::Target
+    {
+	&self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir
+{
+    fn drop(&mut self)
+    {
+	let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn hex()
+    {
+	assert_eq!(decode_hex("00fFa9"), Ok(vec![0x00, 0xff, 0xa9]));
+	assert_eq!(decode_hex("00f"), Err(HexDecodeError::OddLength));
+	assert_eq!(decode_hex("0g"), Err(HexDecodeError::InvalidDigit('g', 1)));
+	let raw = [0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef];
+	assert_eq!(decode_hex(&raw.hex().into_string()).as_deref(), Ok(&raw[..]));
+    }
+}
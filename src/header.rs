@@ -0,0 +1,442 @@
+//! Self-describing ciphertext container header
+//!
+//! # Format
+//! All integers are big endian.
+//! ```text
+//! magic      [u8; 4]   "CC20"
+//! version    u8        `VERSION`
+//! algorithm  u8        see `cha::CipherSuite`
+//! flags      u16       see `flags`
+//! nonce_len  u8
+//! nonce      [u8; nonce_len]
+//! sections   ...       each `kind: u8, len: u16, data: [u8; len]`, ending with a section of kind `END`
+//! ```
+//! The header is authenticated as associated data of the ciphertext that follows it, except for the sections whose kind has the `UNBOUND` bit set (which may be rewritten without re-encrypting the body.)
+use super::*;
+use key::Nonce;
+use cha::CipherSuite;
+
+use std::{
+    fmt, error,
+    io,
+    convert::TryFrom,
+};
+
+/// The bytes every header starts with
+pub const MAGIC: [u8; 4] = *b"CC20";
+/// The current header format version
+pub const VERSION: u8 = 1;
+/// The largest header that will be read
+pub const MAX_SIZE: usize = 1024 * 1024;
+/// The padding reserved in a header with a file key, so that recipients can be added to it in place (see `Header::to_bytes_sized()`.)
+///
+/// This is room for a few more X25519 or passphrase stanzas.
+pub const RESERVED_SIZE: u16 = 512;
+
+/// Header flags
+pub mod flags
+{
+    /// The ciphertext is chunked, the header must contain a `SEGMENT_SIZE` section.
+    pub const CHUNKED: u16 = 1 << 0;
+    /// The authentication tag is not part of the ciphertext, it is kept separately.
+    pub const DETACHED: u16 = 1 << 1;
+
+    /// All flags this version understands
+    pub const ALL: u16 = CHUNKED | DETACHED;
+}
+
+/// Section kinds
+pub mod section
+{
+    /// Marks the end of the header
+    pub const END: u8 = 0x00;
+    /// The plaintext segment size of a chunked ciphertext (`u32`)
+    pub const SEGMENT_SIZE: u8 = 0x01;
+    /// Key derivation parameters of a passphrase, see `kdf::Kdf::to_bytes()`
+    pub const KDF: u8 = 0x02;
+    /// The fingerprint of the key the ciphertext was encrypted with, see `key::Key::fingerprint()`
+    pub const KEY_ID: u8 = 0x03;
+    /// The file key wrapped for a recipient, see `recipient::Stanza`
+    pub const RECIPIENT: u8 = 0x81;
+    /// Ignored space, used to allow the header to be rewritten in place
+    pub const PADDING: u8 = 0xff;
+
+    /// Sections with this bit set in their kind are not authenticated along with the ciphertext
+    pub const UNBOUND: u8 = 0x80;
+}
+
+impl TryFrom<u8> for CipherSuite
+{
+    type Error = Error;
+
+    #[inline] fn try_from(from: u8) -> Result<Self, Self::Error>
+    {
+	match from {
+	    1 => Ok(Self::ChaCha20Poly1305),
+	    2 => Ok(Self::XChaCha20Poly1305),
+	    3 => Ok(Self::Aes256Gcm),
+	    other => Err(Error::UnknownAlgorithm(other)),
+	}
+    }
+}
+
+/// A section of the header following the nonce
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Section
+{
+    SegmentSize(u32),
+    Kdf(Vec<u8>),
+    KeyId(key::Fingerprint),
+    Recipient(Vec<u8>),
+    Padding(u16),
+}
+
+impl Section
+{
+    /// The kind byte of this section
+    #[inline] pub fn kind(&self) -> u8
+    {
+	match self {
+	    Self::SegmentSize(_) => section::SEGMENT_SIZE,
+	    Self::Kdf(_) => section::KDF,
+	    Self::KeyId(_) => section::KEY_ID,
+	    Self::Recipient(_) => section::RECIPIENT,
+	    Self::Padding(_) => section::PADDING,
+	}
+    }
+
+    /// Is this section authenticated along with the ciphertext?
+    #[inline] pub fn is_bound(&self) -> bool
+    {
+	self.kind() & section::UNBOUND == 0
+    }
+
+    fn encode_into(&self, output: &mut Vec<u8>) -> Result<(), Error>
+    {
+	let data: std::borrow::Cow<'_, [u8]> = match self {
+	    Self::SegmentSize(size) => size.to_be_bytes().to_vec().into(),
+	    Self::Kdf(data) | Self::Recipient(data) => data[..].into(),
+	    Self::KeyId(id) => id.as_ref().into(),
+	    &Self::Padding(len) => vec![0u8; usize::from(len)].into(),
+	};
+	let len = u16::try_from(data.len()).map_err(|_| Error::TooLarge)?;
+	output.push(self.kind());
+	output.extend_from_slice(&len.to_be_bytes());
+	output.extend_from_slice(&data[..]);
+	Ok(())
+    }
+
+    fn decode(kind: u8, data: &[u8]) -> Result<Self, Error>
+    {
+	Ok(match kind {
+	    section::SEGMENT_SIZE => Self::SegmentSize(u32::from_be_bytes(data.try_into().map_err(|_| Error::Malformed("segment size"))?)),
+	    section::KDF => Self::Kdf(data.to_vec()),
+	    section::KEY_ID => Self::KeyId(key::Fingerprint::from_bytes(data.try_into().map_err(|_| Error::Malformed("key ID"))?)),
+	    section::RECIPIENT => Self::Recipient(data.to_vec()),
+	    section::PADDING => Self::Padding(data.len() as u16),
+	    other => return Err(Error::UnknownSection(other)),
+	})
+    }
+}
+
+/// The header of a ciphertext
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Header
+{
+    pub suite: CipherSuite,
+    pub flags: u16,
+    pub nonce: Nonce,
+    pub sections: Vec<Section>,
+}
+
+impl Header
+{
+    /// Create a header describing a ciphertext with this cipher suite, nonce and framing
+    pub fn new(suite: CipherSuite, nonce: Nonce, framing: cha::Framing) -> Result<Self, Error>
+    {
+	if nonce.as_ref().len() != suite.nonce_size() {
+	    return Err(Error::Malformed("nonce"));
+	}
+	let mut this = Self {
+	    suite,
+	    flags: 0,
+	    nonce,
+	    sections: Vec::new(),
+	};
+	match framing {
+	    cha::Framing::Single => (),
+	    cha::Framing::Chunked(size) => {
+		this.flags |= flags::CHUNKED;
+		this.sections.push(Section::SegmentSize(u32::try_from(size).map_err(|_| Error::TooLarge)?));
+	    },
+	    cha::Framing::Detached => this.flags |= flags::DETACHED,
+	    cha::Framing::Unauthenticated(_) => return Err(Error::Unauthenticated),
+	}
+	Ok(this)
+    }
+
+    /// The framing of the ciphertext this header describes
+    pub fn framing(&self) -> Result<cha::Framing, Error>
+    {
+	match (self.flags & flags::CHUNKED != 0, self.flags & flags::DETACHED != 0) {
+	    (false, false) => return Ok(cha::Framing::Single),
+	    (false, true) => return Ok(cha::Framing::Detached),
+	    (true, true) => return Err(Error::Malformed("flags")),
+	    (true, false) => (),
+	}
+	match self.sections.iter().find_map(|s| if let Section::SegmentSize(size) = s { Some(*size) } else { None }) {
+	    Some(0) | None => Err(Error::Malformed("segment size")),
+	    Some(size) => Ok(cha::Framing::Chunked(size as usize)),
+	}
+    }
+
+    /// The file key of the ciphertext wrapped for each of its recipients, if it has a file key
+    ///
+    /// Fails with `MultiplePassphrases` if more than one of them is a passphrase (see `recipient::check_passphrases()`.)
+    pub fn recipients(&self) -> Result<Vec<recipient::Stanza>, Error>
+    {
+	let stanzas: Vec<_> = self.sections.iter()
+	    .filter_map(|s| if let Section::Recipient(data) = s { Some(data) } else { None })
+	    .map(|data| recipient::Stanza::from_bytes(&data[..]))
+	    .collect::<Result<_, _>>()?;
+	recipient::check_passphrases(stanzas.iter().map(|stanza| &stanza.kind))?;
+	Ok(stanzas)
+    }
+
+    /// The KDF that the key of the ciphertext was derived from a passphrase with, if any
+    pub fn kdf(&self) -> Result<Option<kdf::Kdf>, Error>
+    {
+	self.sections.iter()
+	    .find_map(|s| if let Section::Kdf(data) = s { Some(data) } else { None })
+	    .map(|data| kdf::Kdf::from_bytes(&data[..]).map_err(Error::from))
+	    .transpose()
+    }
+
+    /// The fingerprint of the key the ciphertext was encrypted with, if it was recorded
+    pub fn key_id(&self) -> Option<key::Fingerprint>
+    {
+	self.sections.iter().find_map(|s| if let Section::KeyId(id) = s { Some(*id) } else { None })
+    }
+
+    fn encode_with(&self, mut include: impl FnMut(&Section) -> bool) -> Result<Vec<u8>, Error>
+    {
+	let nonce: &[u8] = self.nonce.as_ref();
+	let mut output = Vec::with_capacity(MAGIC.len() + 5 + nonce.len() + 3);
+	output.extend_from_slice(&MAGIC[..]);
+	output.push(VERSION);
+	output.push(self.suite as u8);
+	output.extend_from_slice(&self.flags.to_be_bytes());
+	output.push(nonce.len() as u8);
+	output.extend_from_slice(nonce);
+	for section in self.sections.iter().filter(|s| include(s)) {
+	    section.encode_into(&mut output)?;
+	}
+	output.extend_from_slice(&[section::END, 0, 0]);
+	Ok(output)
+    }
+
+    /// Encode this header
+    #[inline] pub fn to_bytes(&self) -> Result<Vec<u8>, Error>
+    {
+	self.encode_with(|_| true)
+    }
+
+    /// Encode this header as exactly `size` bytes, so that it can replace a header of that size in place.
+    ///
+    /// Any padding sections are replaced by ones that fill the space that is left. Fails with `Error::NoRoom` if the header does not fit.
+    pub fn to_bytes_sized(&self, size: usize) -> Result<Vec<u8>, Error>
+    {
+	let mut this = self.clone();
+	this.sections.retain(|s| !matches!(s, Section::Padding(_)));
+	let mut left = size.checked_sub(this.to_bytes()?.len()).ok_or(Error::NoRoom)?;
+	while left > 0 {
+	    // Each padding section takes 3 bytes before its data, so a full one must leave room for another
+	    let len = match left.checked_sub(3).ok_or(Error::NoRoom)? {
+		len if len > usize::from(u16::MAX) => usize::from(u16::MAX) - 3,
+		len => len,
+	    };
+	    this.sections.push(Section::Padding(len as u16));
+	    left -= 3 + len;
+	}
+	this.to_bytes()
+    }
+
+    /// The encoding of the parts of this header that are authenticated along with the ciphertext
+    #[inline] pub fn aad(&self) -> Result<Vec<u8>, Error>
+    {
+	self.encode_with(Section::is_bound)
+    }
+
+    /// Parse a header from the start of `input`.
+    ///
+    /// Returns the header and its encoded length, or `None` if `input` ends before the header does.
+    pub fn parse(input: &[u8]) -> Result<Option<(Self, usize)>, Error>
+    {
+	let mut reader = Reader(input, 0);
+	macro_rules! take {
+	    ($n:expr) => {
+		match reader.take($n)? {
+		    Some(v) => v,
+		    None => return Ok(None),
+		}
+	    };
+	}
+	if take!(MAGIC.len()) != &MAGIC[..] {
+	    return Err(Error::BadMagic);
+	}
+	match take!(1)[0] {
+	    VERSION => (),
+	    other => return Err(Error::UnsupportedVersion(other)),
+	}
+	let suite = CipherSuite::try_from(take!(1)[0])?;
+	let flags = u16::from_be_bytes(take!(2).try_into().unwrap());
+	if flags & !flags::ALL != 0 {
+	    return Err(Error::UnknownFlags(flags & !flags::ALL));
+	}
+	let nonce_len = usize::from(take!(1)[0]);
+	if nonce_len != suite.nonce_size() {
+	    return Err(Error::Malformed("nonce"));
+	}
+	let nonce = Nonce::from_slice(take!(nonce_len)).ok_or(Error::Malformed("nonce"))?;
+
+	let mut sections = Vec::new();
+	loop {
+	    let kind = take!(1)[0];
+	    let len = usize::from(u16::from_be_bytes(take!(2).try_into().unwrap()));
+	    let data = take!(len);
+	    if kind == section::END {
+		break;
+	    }
+	    sections.push(Section::decode(kind, data)?);
+	}
+
+	let this = Self {
+	    suite,
+	    flags,
+	    nonce,
+	    sections,
+	};
+	// Check the framing is valid
+	this.framing()?;
+	Ok(Some((this, reader.1)))
+    }
+}
+
+/// Rewrite the header at the start of `input` with `rewrite`, returning the encoding of the new header to write over the old one.
+///
+/// The new header is the same size as the old one (see `Header::to_bytes_sized()`,) so nothing following it needs to be moved.
+/// Fails with `BoundSectionsChanged` if `rewrite` changes the parts of the header that are authenticated, which would make the ciphertext following it fail authentication.
+pub fn rewrite(input: &[u8], rewrite: impl FnOnce(&Header) -> Result<Header, Error>) -> Result<Vec<u8>, Error>
+{
+    let (old, len) = Header::parse(input)?.ok_or(Error::Truncated)?;
+    let new = rewrite(&old)?;
+    if new.aad()? != old.aad()? {
+	return Err(Error::BoundSectionsChanged);
+    }
+    new.to_bytes_sized(len)
+}
+
+/// Reads fields from the start of a buffer
+struct Reader<'a>(&'a [u8], usize);
+
+impl<'a> Reader<'a>
+{
+    #[inline] fn take(&mut self, n: usize) -> Result<Option<&'a [u8]>, Error>
+    {
+	if self.1 + n > MAX_SIZE {
+	    return Err(Error::TooLarge);
+	}
+	let field = self.0.get(self.1..(self.1 + n));
+	if field.is_some() {
+	    self.1 += n;
+	}
+	Ok(field)
+    }
+}
+
+/// An error reading or writing a header
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Error
+{
+    /// The input does not start with `MAGIC`
+    BadMagic,
+    UnsupportedVersion(u8),
+    UnknownAlgorithm(u8),
+    UnknownFlags(u16),
+    UnknownSection(u8),
+    /// A field of the header is invalid
+    Malformed(&'static str),
+    /// The header (or one of its sections) is too large
+    TooLarge,
+    /// The input ended before the header did
+    Truncated,
+    /// An IV (or cipher suite) was given that does not match the header's
+    NonceMismatch,
+    /// A framing was given that does not match the header's
+    FramingMismatch,
+    /// A header was requested for an unauthenticated ciphertext, which could not authenticate it
+    Unauthenticated,
+    /// The KDF section is invalid, or the key could not be derived with it
+    Kdf(kdf::Error),
+    /// A passphrase was given, but the ciphertext was not encrypted with one
+    MissingKdf,
+    /// A recipient section is of an unknown kind
+    UnknownRecipient(u8),
+    /// An identity was given, but the ciphertext was not encrypted to a public key
+    MissingRecipient,
+    /// None of the given keys, passphrases or identities unwrap the file key of the ciphertext
+    NotRecipient,
+    /// The X25519 key agreement with a public key failed (it is invalid, or of low order)
+    KeyAgreement,
+    /// The ciphertext's recipients were to be changed, but it does not have a file key
+    NoFileKey,
+    /// Changing the ciphertext's recipients would leave none
+    NoRecipients,
+    /// A header does not fit in the space of the header it was to replace
+    NoRoom,
+    /// A header was to be replaced by one whose authenticated parts differ
+    BoundSectionsChanged,
+    /// The file key is (or would be) wrapped with more than one passphrase
+    MultiplePassphrases,
+}
+
+impl error::Error for Error{}
+impl fmt::Display for Error
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+	match self {
+	    Self::BadMagic => f.write_str("input does not start with a ciphertext header (use `--raw` for headerless ciphertexts)"),
+	    Self::UnsupportedVersion(v) => write!(f, "unsupported header version {}", v),
+	    Self::UnknownAlgorithm(a) => write!(f, "unknown cipher algorithm {}", a),
+	    Self::UnknownFlags(fl) => write!(f, "unknown header flags {:#06x}", fl),
+	    Self::UnknownSection(s) => write!(f, "unknown header section {:#04x}", s),
+	    Self::Malformed(field) => write!(f, "malformed header field: {}", field),
+	    Self::TooLarge => f.write_str("header is too large"),
+	    Self::Truncated => f.write_str("input ends before the header does"),
+	    Self::NonceMismatch => f.write_str("the given IV does not match the ciphertext's header"),
+	    Self::FramingMismatch => f.write_str("the given framing does not match the ciphertext's header"),
+	    Self::Unauthenticated => f.write_str("an unauthenticated ciphertext cannot have a header (use `--raw`)"),
+	    Self::Kdf(err) => write!(f, "{}", err),
+	    Self::MissingKdf => f.write_str("the ciphertext was not encrypted with a passphrase"),
+	    Self::UnknownRecipient(k) => write!(f, "unknown recipient kind {}", k),
+	    Self::MissingRecipient => f.write_str("the ciphertext was not encrypted to a public key"),
+	    Self::NotRecipient => f.write_str("the ciphertext was not encrypted to any of the given keys, passphrases or identities"),
+	    Self::KeyAgreement => f.write_str("X25519 key agreement failed, the public key is invalid"),
+	    Self::NoFileKey => f.write_str("the ciphertext does not have a wrapped file key, so its recipients cannot be changed (encrypt it with `--wrap`)"),
+	    Self::NoRecipients => f.write_str("no recipients would be left that can decrypt the ciphertext"),
+	    Self::NoRoom => f.write_str("the new recipients do not fit in the space of the header, which cannot grow without re-encrypting the ciphertext"),
+	    Self::BoundSectionsChanged => f.write_str("the authenticated parts of a header cannot be rewritten in place"),
+	    Self::MultiplePassphrases => f.write_str("a ciphertext can only have one passphrase recipient (use `--remove-passphrase` to replace it)"),
+	}
+    }
+}
+
+impl From<Error> for io::Error
+{
+    #[inline] fn from(from: Error) -> Self
+    {
+	io::Error::new(io::ErrorKind::InvalidData, from)
+    }
+}
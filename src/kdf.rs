@@ -0,0 +1,442 @@
+//! Deriving keys from passphrases and other secrets
+use openssl::{
+    pkcs5,
+    md::Md,
+    pkey::Id,
+    pkey_ctx::PkeyCtx,
+    error::ErrorStack,
+};
+use std::{
+    fmt, error,
+    str,
+    time::{Duration, Instant},
+};
+use crate::key::Key;
+use crate::header::Error as HeaderError;
+
+/// The size of the random salt of a `Kdf`
+pub const SALT_SIZE: usize = 16;
+
+/// The most memory a `Kdf` may use when deriving a key
+pub const MAX_MEMORY: u64 = 1 << 30;
+/// The most memory a `Kdf` may fill (counting each pass over it) when deriving a key, which bounds how long it takes
+pub const MAX_WORK: u64 = MAX_MEMORY * 64;
+
+mod id {
+    pub const SCRYPT: u8 = 1;
+    pub const ARGON2ID: u8 = 2;
+}
+
+/// A key derivation algorithm
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub enum Algorithm
+{
+    Scrypt,
+    #[default]
+    Argon2id,
+}
+
+impl Algorithm
+{
+    /// The name of this algorithm, as given to `--kdf`
+    #[inline] pub const fn name(self) -> &'static str
+    {
+	match self {
+	    Self::Scrypt => "scrypt",
+	    Self::Argon2id => "argon2id",
+	}
+    }
+
+    /// The algorithm with this `name()`
+    pub fn from_name(name: &str) -> Option<Self>
+    {
+	[Self::Scrypt, Self::Argon2id].into_iter().find(|a| a.name().eq_ignore_ascii_case(name))
+    }
+
+    /// This algorithm with its default parameters and a new random salt
+    pub fn kdf(self) -> Kdf
+    {
+	match self {
+	    Self::Scrypt => Kdf::scrypt(17, 8, 1),
+	    // The second recommended option of RFC 9106
+	    Self::Argon2id => Kdf::argon2id(1 << 16, 3, 4),
+	}
+    }
+}
+
+impl fmt::Display for Algorithm
+{
+    #[inline] fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+	f.write_str(self.name())
+    }
+}
+
+/// A key derivation function and its parameters, including the salt.
+///
+/// These are stored in the `header::Section::Kdf` of a ciphertext encrypted with a passphrase, so that it can be decrypted with just the passphrase.
+///
+/// # Parameters
+/// Parameters are written (by `Display`, and for `FromStr`) as `<algorithm>[:<name>=<value>,...]`, e.g. `argon2id:m=65536,t=3,p=4`.
+/// Parameters that are not given take their default values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Kdf
+{
+    /// scrypt with a cost of `2^log_n`, a block size of `r` and a parallelism of `p`
+    Scrypt {
+	log_n: u8,
+	r: u32,
+	p: u32,
+	salt: [u8; SALT_SIZE],
+    },
+    /// Argon2id (version 0x13) with a memory cost of `m` KiB, a time cost of `t` passes and a parallelism of `p` lanes
+    Argon2id {
+	m: u32,
+	t: u32,
+	p: u32,
+	salt: [u8; SALT_SIZE],
+    },
+}
+
+impl Default for Kdf
+{
+    /// The default algorithm with its default parameters and a new random salt
+    #[inline] fn default() -> Self
+    {
+	Algorithm::default().kdf()
+    }
+}
+
+/// Generate a new random salt
+fn salt() -> [u8; SALT_SIZE]
+{
+    let mut salt = [0u8; SALT_SIZE];
+    getrandom::getrandom(&mut salt[..]).expect("rng fatal");
+    salt
+}
+
+impl Kdf
+{
+    /// scrypt with these parameters and a new random salt
+    #[inline] pub fn scrypt(log_n: u8, r: u32, p: u32) -> Self
+    {
+	Self::Scrypt { log_n, r, p, salt: salt() }
+    }
+
+    /// Argon2id with these parameters and a new random salt
+    #[inline] pub fn argon2id(m: u32, t: u32, p: u32) -> Self
+    {
+	Self::Argon2id { m, t, p, salt: salt() }
+    }
+
+    /// The algorithm of this KDF
+    #[inline] pub fn algorithm(&self) -> Algorithm
+    {
+	match self {
+	    Self::Scrypt { .. } => Algorithm::Scrypt,
+	    Self::Argon2id { .. } => Algorithm::Argon2id,
+	}
+    }
+
+    /// The amount of memory deriving a key uses, or `None` if the parameters are invalid
+    pub fn memory(&self) -> Option<u64>
+    {
+	match *self {
+	    Self::Scrypt { log_n, r, p, .. } => {
+		if log_n == 0 || log_n >= 64 || r == 0 || p == 0 {
+		    return None;
+		}
+		// `V` is `128 * r * N` bytes, and `B` is `128 * r * p`; OpenSSL also counts 2 blocks of scratch space in `V`.
+		(1u64 << log_n).checked_add(2)?
+		    .checked_add(u64::from(p))?
+		    .checked_mul(128)?
+		    .checked_mul(u64::from(r))
+	    },
+	    Self::Argon2id { m, t, p, .. } => {
+		argon2::Params::new(m, t, p, None).ok()?;
+		Some(u64::from(m) * 1024)
+	    },
+	}
+    }
+
+    /// The amount of memory deriving a key fills, counting each pass over it, or `None` if the parameters are invalid
+    pub fn work(&self) -> Option<u64>
+    {
+	let memory = self.memory()?;
+	match *self {
+	    // Each of the `p` blocks fills `V` and then reads it back
+	    Self::Scrypt { p, .. } => memory.checked_mul(u64::from(p))?.checked_mul(2),
+	    Self::Argon2id { t, .. } => memory.checked_mul(u64::from(t)),
+	}
+    }
+
+    /// Check that the parameters are valid, and within `MAX_MEMORY` and `MAX_WORK`
+    pub fn check(self) -> Result<Self, Error>
+    {
+	match (self.memory(), self.work()) {
+	    (None, _) | (_, None) => Err(Error::Malformed),
+	    (Some(memory), _) if memory > MAX_MEMORY => Err(Error::TooExpensive(memory)),
+	    (_, Some(work)) if work > MAX_WORK => Err(Error::TooSlow),
+	    _ => Ok(self),
+	}
+    }
+
+    /// Derive the key for `passphrase`
+    pub fn derive(&self, passphrase: &[u8]) -> Result<Key, Error>
+    {
+	let mut key = Key::default();
+	match *self {
+	    Self::Scrypt { log_n, r, p, salt } => {
+		let maxmem = self.memory().ok_or(Error::Malformed)?;
+		pkcs5::scrypt(passphrase, &salt[..], 1u64 << log_n, r.into(), p.into(), maxmem, &mut key.expose_secret_mut()[..])
+		    .map_err(|_| Error::Derivation)?;
+	    },
+	    Self::Argon2id { m, t, p, salt } => {
+		let params = argon2::Params::new(m, t, p, Some(crate::cha::KEY_SIZE)).map_err(|_| Error::Malformed)?;
+		argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+		    .hash_password_into(passphrase, &salt[..], &mut key.expose_secret_mut()[..])
+		    .map_err(|_| Error::Derivation)?;
+	    },
+	}
+	Ok(key)
+    }
+
+    /// Encode this KDF as the contents of a `header::Section::Kdf`
+    pub fn to_bytes(self) -> Vec<u8>
+    {
+	let mut output = Vec::with_capacity(1 + 12 + SALT_SIZE);
+	let salt = match self {
+	    Self::Scrypt { log_n, r, p, salt } => {
+		output.push(id::SCRYPT);
+		output.push(log_n);
+		output.extend_from_slice(&r.to_be_bytes());
+		output.extend_from_slice(&p.to_be_bytes());
+		salt
+	    },
+	    Self::Argon2id { m, t, p, salt } => {
+		output.push(id::ARGON2ID);
+		output.extend_from_slice(&m.to_be_bytes());
+		output.extend_from_slice(&t.to_be_bytes());
+		output.extend_from_slice(&p.to_be_bytes());
+		salt
+	    },
+	};
+	output.extend_from_slice(&salt[..]);
+	output
+    }
+
+    /// Decode a KDF from the contents of a `header::Section::Kdf`.
+    ///
+    /// Parameters that fail `check()` are rejected.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, Error>
+    {
+	let (&kind, data) = data.split_first().ok_or(Error::Malformed)?;
+	let u32_at = |at: usize| u32::from_be_bytes(data[at..(at + 4)].try_into().unwrap());
+	let this = match kind {
+	    id::SCRYPT if data.len() == 1 + 8 + SALT_SIZE => Self::Scrypt {
+		log_n: data[0],
+		r: u32_at(1),
+		p: u32_at(5),
+		salt: data[9..].try_into().unwrap(),
+	    },
+	    id::ARGON2ID if data.len() == 12 + SALT_SIZE => Self::Argon2id {
+		m: u32_at(0),
+		t: u32_at(4),
+		p: u32_at(8),
+		salt: data[12..].try_into().unwrap(),
+	    },
+	    id::SCRYPT | id::ARGON2ID => return Err(Error::Malformed),
+	    other => return Err(Error::UnknownAlgorithm(other)),
+	};
+	this.check()
+    }
+
+    /// Find parameters for `algorithm` that take about `target` to derive a key on this machine, using at most `max_memory`.
+    ///
+    /// Memory is the main cost: it is raised as far as `max_memory` allows (as long as one pass fits in `target`,) then the number of passes is raised to fill `target`.
+    /// Returns the parameters, and how long they took to derive a key.
+    pub fn calibrate(algorithm: Algorithm, target: Duration, max_memory: u64) -> Result<(Self, Duration), Error>
+    {
+	let time = |kdf: Self| -> Result<Duration, Error> {
+	    let start = Instant::now();
+	    kdf.check()?.derive(b"kdf-bench")?;
+	    Ok(start.elapsed())
+	};
+	let max_memory = max_memory.min(MAX_MEMORY);
+	match algorithm {
+	    Algorithm::Scrypt => {
+		// scrypt's time and memory both grow with N, so double it for as long as it fits
+		let mut best = None;
+		for log_n in 10..64 {
+		    let kdf = Self::scrypt(log_n, 8, 1);
+		    match kdf.memory() {
+			Some(memory) if memory <= max_memory => (),
+			_ => break,
+		    }
+		    let elapsed = time(kdf)?;
+		    if elapsed > target && best.is_some() {
+			break;
+		    }
+		    best = Some((kdf, elapsed));
+		}
+		best.ok_or_else(|| Error::TooExpensive(Self::scrypt(10, 8, 1).memory().unwrap_or(0)))
+	    },
+	    Algorithm::Argon2id => {
+		const LANES: u32 = 4;
+		const MIN_MEMORY: u32 = 8 * LANES * 2;
+		let mut m = u32::try_from(max_memory / 1024).unwrap_or(u32::MAX);
+		if m < MIN_MEMORY {
+		    return Err(Error::TooExpensive(u64::from(MIN_MEMORY) * 1024));
+		}
+		let (mut kdf, mut elapsed) = loop {
+		    let kdf = Self::argon2id(m, 1, LANES);
+		    let elapsed = time(kdf)?;
+		    if elapsed <= target || m / 2 < MIN_MEMORY {
+			break (kdf, elapsed);
+		    }
+		    m /= 2;
+		};
+		// Each pass takes about as long as the first
+		let mut t = (target.as_secs_f64() / elapsed.as_secs_f64().max(f64::EPSILON)) as u32;
+		while t > 1 && Self::argon2id(m, t, LANES).check().is_err() {
+		    t -= 1;
+		}
+		if t > 1 {
+		    kdf = Self::argon2id(m, t, LANES);
+		    elapsed = time(kdf)?;
+		}
+		Ok((kdf, elapsed))
+	    },
+	}
+    }
+}
+
+impl fmt::Display for Kdf
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+	match self {
+	    Self::Scrypt { log_n, r, p, .. } => write!(f, "scrypt:log_n={},r={},p={}", log_n, r, p),
+	    Self::Argon2id { m, t, p, .. } => write!(f, "argon2id:m={},t={},p={}", m, t, p),
+	}
+    }
+}
+
+impl str::FromStr for Kdf
+{
+    type Err = Error;
+
+    /// Parse parameters written as `<algorithm>[:<name>=<value>,...]`, with a new random salt
+    fn from_str(s: &str) -> Result<Self, Self::Err>
+    {
+	let (name, params) = s.split_once(':').unwrap_or((s, ""));
+	let mut this = Algorithm::from_name(name.trim()).ok_or(Error::Malformed)?.kdf();
+	for param in params.split(',').filter(|p| !p.trim().is_empty()) {
+	    let (name, value) = param.split_once('=').ok_or(Error::Malformed)?;
+	    let value: u32 = value.trim().parse().map_err(|_| Error::Malformed)?;
+	    match (&mut this, name.trim()) {
+		(Self::Scrypt { log_n, .. }, "log_n") => *log_n = u8::try_from(value).map_err(|_| Error::Malformed)?,
+		(Self::Scrypt { r, .. }, "r") => *r = value,
+		(Self::Argon2id { m, .. }, "m") => *m = value,
+		(Self::Argon2id { t, .. }, "t") => *t = value,
+		(Self::Scrypt { p, .. } | Self::Argon2id { p, .. }, "p") => *p = value,
+		_ => return Err(Error::Malformed),
+	    }
+	}
+	this.check()
+    }
+}
+
+/// HKDF-SHA256 (RFC 5869): derive `output.len()` bytes from the secret `ikm`, bound to `salt` and `info`.
+pub fn hkdf(ikm: &[u8], salt: &[u8], info: &[u8], output: &mut [u8]) -> Result<(), ErrorStack>
+{
+    let mut ctx = PkeyCtx::new_id(Id::HKDF)?;
+    ctx.derive_init()?;
+    ctx.set_hkdf_md(Md::sha256())?;
+    ctx.set_hkdf_key(ikm)?;
+    if !salt.is_empty() {
+	ctx.set_hkdf_salt(salt)?;
+    }
+    ctx.add_hkdf_info(info)?;
+    ctx.derive(Some(output))?;
+    Ok(())
+}
+
+/// An error reading the KDF of a header, or deriving a key with it
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Error
+{
+    UnknownAlgorithm(u8),
+    /// The parameters are the wrong size or invalid
+    Malformed,
+    /// Deriving the key would use this much memory, which is more than `MAX_MEMORY`
+    TooExpensive(u64),
+    /// Deriving the key would fill more memory than `MAX_WORK`
+    TooSlow,
+    /// Deriving the key failed
+    Derivation,
+}
+
+impl error::Error for Error{}
+impl fmt::Display for Error
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+	match self {
+	    Self::UnknownAlgorithm(a) => write!(f, "unknown key derivation algorithm {}", a),
+	    Self::Malformed => f.write_str("malformed key derivation parameters"),
+	    Self::TooExpensive(memory) => write!(f, "key derivation would use {} MiB of memory, more than the limit of {} MiB", memory >> 20, MAX_MEMORY >> 20),
+	    Self::TooSlow => f.write_str("key derivation would take too long"),
+	    Self::Derivation => f.write_str("failed to derive the key from the passphrase"),
+	}
+    }
+}
+
+impl From<Error> for HeaderError
+{
+    #[inline] fn from(from: Error) -> Self
+    {
+	HeaderError::Kdf(from)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::ext::*;
+
+    /// RFC 5869, test case 1
+    #[test]
+    fn hkdf()
+    {
+	let mut okm = [0u8; 42];
+	super::hkdf(&[0x0b; 22], &(0..=0x0c).collect::<Vec<u8>>(), &(0xf0..=0xf9).collect::<Vec<u8>>(), &mut okm).unwrap();
+	assert_eq!(okm.hex().to_string(), "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf34007208d5b887185865");
+    }
+
+    #[test]
+    fn params()
+    {
+	for kdf in [Kdf::scrypt(10, 8, 1), Kdf::argon2id(256, 2, 2)] {
+	    // Parameters round-trip through `--kdf`, with a new salt
+	    let parsed: Kdf = kdf.to_string().parse().unwrap();
+	    assert_ne!(parsed, kdf);
+	    let params = |kdf: Kdf| {
+		let mut bytes = kdf.to_bytes();
+		bytes.truncate(bytes.len() - SALT_SIZE);
+		bytes
+	    };
+	    assert_eq!(params(parsed), params(kdf));
+	    assert_eq!(Kdf::from_bytes(&kdf.to_bytes()), Ok(kdf));
+	}
+
+	// The parameters are checked before deriving the key
+	assert_eq!(Kdf::from_bytes(&Kdf::scrypt(30, 8, 1).to_bytes()), Err(Error::TooExpensive((1 << 30 | 3) * 128 * 8)));
+	assert_eq!(Kdf::from_bytes(&Kdf::argon2id(1 << 20, 100, 1).to_bytes()), Err(Error::TooSlow));
+	assert_eq!(Kdf::from_bytes(&Kdf::scrypt(10, 8, 1).to_bytes()[..10]), Err(Error::Malformed));
+	assert_eq!("argon2id:m=4,p=1".parse::<Kdf>(), Err(Error::Malformed));
+	assert_eq!("scrypt:m=1024".parse::<Kdf>(), Err(Error::Malformed));
+    }
+}
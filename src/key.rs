@@ -1,30 +1,147 @@
 use getrandom::getrandom;
-use std::{fmt, str};
+use std::{fmt, str, error};
 use crate::cha::{
     KEY_SIZE,
     IV_SIZE,
+    XNONCE_SIZE,
 };
 use crate::ext::*;
+use crate::secure::Locked;
+use zeroize::Zeroizing;
+
+/// A secret key, zeroed out when it is dropped.
+///
+/// It is not `Copy`, so that every copy of the secret is explicit (and dropped), and its `Display` and `Debug` output only show its fingerprint. The bytes are only accessible through `expose_secret()` (or as text through `encode()`.)
+/// They are stored on the heap, locked into memory and excluded from core dumps (see `secure::Locked`.)
+#[derive(Clone, Default)]
+pub struct Key(Locked<Box<[u8; KEY_SIZE]>>);
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Copy, Default)]
 #[repr(transparent)]
-pub struct Key([u8; KEY_SIZE]);
+pub struct IV([u8; IV_SIZE]);
 
+/// The extended nonce of XChaCha20-Poly1305
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Copy, Default)]
 #[repr(transparent)]
-pub struct IV([u8; IV_SIZE]);
+pub struct XNonce([u8; XNONCE_SIZE]);
+
+/// The size of a key's `Fingerprint`
+pub const FINGERPRINT_SIZE: usize = 32;
+/// The number of bytes of a `Fingerprint` shown by `Fingerprint::short()`
+pub const SHORT_FINGERPRINT_SIZE: usize = 8;
+
+/// A public identifier of a key: a SHA-256 hash of it, which can be shared (and recorded in headers) without revealing the key.
+///
+/// It is shown in full hex, or as its first `SHORT_FINGERPRINT_SIZE` bytes by `short()` (which is how a `Key` is shown.)
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Copy)]
+#[repr(transparent)]
+pub struct Fingerprint([u8; FINGERPRINT_SIZE]);
+
+/// The nonce of a ciphertext, sized for its algorithm
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Copy)]
+pub enum Nonce
+{
+    IV(IV),
+    XNonce(XNonce),
+}
 
 impl Key
 {
     #[inline] pub fn from_bytes(k: [u8; KEY_SIZE]) -> Self
     {
-	Self(k)
+	Self(Locked::new(Box::new(k)))
     }
     pub fn new() -> Self
     {
-	let mut output = [0u8; KEY_SIZE];
-	getrandom(&mut output[..]).expect("rng fatal");
-	Self(output)
+	let mut output = Self::default();
+	getrandom(&mut output.0[..]).expect("rng fatal");
+	output
+    }
+
+    /// Decode a key stored as its `KEY_SIZE` raw bytes, or as text in any `Encoding` (ignoring surrounding whitespace.)
+    pub fn decode(data: &[u8]) -> Result<Self, KeyParseError>
+    {
+	if let Ok(raw) = data.try_into() {
+	    return Ok(Self::from_bytes(raw));
+	}
+	// Parsed in place, so that no copy of the key's text is left behind
+	str::from_utf8(data).map_err(KeyParseError::Utf8)?.trim().parse()
+    }
+
+    /// The bytes of the key
+    #[inline] pub fn expose_secret(&self) -> &[u8; KEY_SIZE]
+    {
+	&self.0
+    }
+
+    /// The bytes of the key, to write a derived key into
+    #[inline] pub fn expose_secret_mut(&mut self) -> &mut [u8; KEY_SIZE]
+    {
+	&mut self.0
+    }
+
+    /// The key as text in `encoding` (without the prefix.) This is the only way to format the key itself.
+    #[inline] pub fn encode(&self, encoding: Encoding) -> Zeroizing<String>
+    {
+	Zeroizing::new(encoding.encode(&self.0[..]))
+    }
+
+    /// Derive the subkey for `label` from this (master) key with HKDF-SHA256.
+    ///
+    /// `label` is the HKDF info as it is, so any HKDF-SHA256 implementation derives the same key.
+    pub fn derive(&self, label: &[u8], salt: &[u8]) -> Self
+    {
+	let mut key = Self::default();
+	crate::kdf::hkdf(&self.0[..], salt, label, &mut key.0[..]).expect("hkdf fatal");
+	key
+    }
+
+    /// The fingerprint that identifies this key, which can be logged or compared in place of the key
+    pub fn fingerprint(&self) -> Fingerprint
+    {
+	let mut hasher = openssl::sha::Sha256::new();
+	hasher.update(b"chacha20 key fingerprint");
+	hasher.update(&self.0[..]);
+	Fingerprint(hasher.finish())
+    }
+}
+
+impl Fingerprint
+{
+    #[inline] pub fn from_bytes(k: [u8; FINGERPRINT_SIZE]) -> Self
+    {
+	Self(k)
+    }
+
+    /// The first `SHORT_FINGERPRINT_SIZE` bytes of the fingerprint in hex
+    pub fn short(&self) -> String
+    {
+	self.0[..SHORT_FINGERPRINT_SIZE].iter().copied().into_hex().to_string()
+    }
+
+    /// Does the hex of this fingerprint start with `prefix` (ignoring case)?
+    pub fn matches(&self, prefix: &str) -> bool
+    {
+	let hex = self.to_string();
+	prefix.len() <= hex.len() && hex[..prefix.len()].eq_ignore_ascii_case(prefix)
+    }
+}
+
+impl PartialEq for Key
+{
+    /// Compare in constant time
+    fn eq(&self, other: &Self) -> bool
+    {
+	openssl::memcmp::eq(&self.0[..], &other.0[..])
+    }
+}
+impl Eq for Key{}
+
+impl fmt::Debug for Key
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+	write!(f, "Key({})", self.fingerprint().short())
     }
 }
 
@@ -43,11 +160,25 @@ impl IV
     }
 }
 
+impl XNonce
+{
+    #[inline] pub fn from_bytes(k: [u8; XNONCE_SIZE]) -> Self
+    {
+	Self(k)
+    }
+    pub fn new() -> Self
+    {
+	let mut output = [0u8; XNONCE_SIZE];
+	getrandom(&mut output[..]).expect("rng fatal");
+	Self(output)
+    }
+}
+
 impl From<[u8; KEY_SIZE]> for Key
 {
     #[inline] fn from(from: [u8; KEY_SIZE]) -> Self
     {
-	Self(from)
+	Self::from_bytes(from)
     }
 }
 
@@ -59,23 +190,78 @@ impl From<[u8; IV_SIZE]> for IV
     }
 }
 
+impl From<[u8; XNONCE_SIZE]> for XNonce
+{
+    fn from(from: [u8; XNONCE_SIZE]) -> Self
+    {
+	Self(from)
+    }
+}
+
+impl Nonce
+{
+    /// A nonce of the size of `bytes`, if there is a nonce of that size
+    pub fn from_slice(bytes: &[u8]) -> Option<Self>
+    {
+	if let Ok(iv) = bytes.try_into() {
+	    Some(Self::IV(IV::from_bytes(iv)))
+	} else if let Ok(nonce) = bytes.try_into() {
+	    Some(Self::XNonce(XNonce::from_bytes(nonce)))
+	} else {
+	    None
+	}
+    }
+}
+
+impl From<IV> for Nonce
+{
+    #[inline] fn from(from: IV) -> Self
+    {
+	Self::IV(from)
+    }
+}
+
+impl From<XNonce> for Nonce
+{
+    #[inline] fn from(from: XNonce) -> Self
+    {
+	Self::XNonce(from)
+    }
+}
 
-impl AsRef<[u8]> for Key
+impl AsRef<[u8]> for IV
 {
     fn as_ref(&self) -> &[u8]
     {
 	&self.0[..]
     }
 }
-impl AsRef<[u8]> for IV
+impl AsRef<[u8]> for Fingerprint
+{
+    fn as_ref(&self) -> &[u8]
+    {
+	&self.0[..]
+    }
+}
+impl AsRef<[u8]> for XNonce
 {
     fn as_ref(&self) -> &[u8]
     {
 	&self.0[..]
     }
 }
+impl AsRef<[u8]> for Nonce
+{
+    fn as_ref(&self) -> &[u8]
+    {
+	match self {
+	    Self::IV(iv) => iv.as_ref(),
+	    Self::XNonce(nonce) => nonce.as_ref(),
+	}
+    }
+}
 
-impl AsMut<[u8]> for Key
+impl AsMut<[u8]> for IV
 {
     fn as_mut(&mut self) -> &mut [u8]
     {
@@ -83,7 +269,7 @@ impl AsMut<[u8]> for Key
     }
 }
 
-impl AsMut<[u8]> for IV
+impl AsMut<[u8]> for XNonce
 {
     fn as_mut(&mut self) -> &mut [u8]
     {
@@ -108,9 +294,10 @@ impl AsRef<IV> for IV
 
 impl fmt::Display for Key
 {
+    /// The short fingerprint of the key, see `encode()` for the key itself
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
     {
-	write!(f, "{}", self.0.iter().copied().into_hex())
+	f.write_str(&self.fingerprint().short())
     }
 }
 
@@ -122,32 +309,322 @@ impl fmt::Display for IV
     }
 }
 
+impl fmt::Display for Fingerprint
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+	write!(f, "{}", self.0.iter().copied().into_hex())
+    }
+}
+
+impl fmt::Display for XNonce
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+	write!(f, "{}", self.0.iter().copied().into_hex())
+    }
+}
+
+/// A text encoding of keys, IVs and nonces.
+///
+/// When parsing, the encoding is given by a `<prefix>:` before the text, e.g. `hex:00ff...`.
+/// Without a prefix, text of exactly twice the expected length in hex digits is hex, text with URL-safe base64 characters is `Base64Url`, and anything else is `Base64`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Encoding
+{
+    Hex,
+    /// Standard base64, the default
+    #[default]
+    Base64,
+    /// URL and filename safe base64
+    Base64Url,
+}
+
+impl Encoding
+{
+    pub const ALL: [Self; 3] = [Self::Hex, Self::Base64, Self::Base64Url];
+
+    /// The prefix that selects this encoding, which is also its name
+    #[inline] pub const fn prefix(self) -> &'static str
+    {
+	match self {
+	    Self::Hex => "hex",
+	    Self::Base64 => "b64",
+	    Self::Base64Url => "b64url",
+	}
+    }
+
+    /// The encoding with this `prefix()`
+    pub fn from_prefix(prefix: &str) -> Option<Self>
+    {
+	Self::ALL.into_iter().find(|e| e.prefix().eq_ignore_ascii_case(prefix))
+    }
+
+    /// The encoding of unprefixed `text` that should decode to `expected` bytes
+    pub fn detect(text: &str, expected: usize) -> Self
+    {
+	if text.len() == expected * 2 && text.bytes().all(|b| b.is_ascii_hexdigit()) {
+	    Self::Hex
+	} else if text.bytes().any(|b| b == b'-' || b == b'_') {
+	    Self::Base64Url
+	} else {
+	    Self::Base64
+	}
+    }
+
+    /// Encode `bytes` in this encoding, without the prefix
+    pub fn encode(self, bytes: impl AsRef<[u8]>) -> String
+    {
+	match self {
+	    Self::Hex => bytes.hex().into_string(),
+	    Self::Base64 => base64::encode_config(bytes, base64::STANDARD),
+	    Self::Base64Url => base64::encode_config(bytes, base64::URL_SAFE),
+	}
+    }
+
+    /// Decode `text` (without the prefix) in this encoding
+    pub fn decode(self, text: &str) -> Result<Vec<u8>, KeyParseError>
+    {
+	Ok(match self {
+	    Self::Hex => decode_hex(text)?,
+	    Self::Base64 => base64::decode_config(text, base64::STANDARD)?,
+	    Self::Base64Url => base64::decode_config(text, base64::URL_SAFE)?,
+	})
+    }
+}
+
+impl fmt::Display for Encoding
+{
+    #[inline] fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+	f.write_str(self.prefix())
+    }
+}
+
+/// Decode text in an `Encoding` into exactly `N` bytes.
+///
+/// If `lenient`, input of any length is accepted, zero-padding it or truncating it to `N` bytes.
+pub fn decode_text<const N: usize>(s: &str, lenient: bool) -> Result<[u8; N], KeyParseError>
+{
+    let (encoding, text) = match s.split_once(':') {
+	Some((prefix, text)) => (Encoding::from_prefix(prefix).ok_or_else(|| KeyParseError::UnknownEncoding(prefix.to_owned()))?, text),
+	None => (Encoding::detect(s, N), s),
+    };
+    let buffer = Zeroizing::new(encoding.decode(text)?);
+    if !lenient && buffer.len() != N {
+	return Err(KeyParseError::Length { expected: N, actual: buffer.len() });
+    }
+
+    let mut output = [0u8; N];
+    let sz = std::cmp::min(N, buffer.len());
+    output[..sz].copy_from_slice(&buffer[..sz]);
+    Ok(output)
+}
+
+impl Key
+{
+    /// Parse a key of any length, zero-padding or truncating it to `KEY_SIZE` bytes.
+    ///
+    /// # Security
+    /// A truncated or mistyped key is silently accepted, and may be weak. Prefer `FromStr`, which rejects keys of the wrong length.
+    #[inline] pub fn parse_lenient(s: &str) -> Result<Self, KeyParseError>
+    {
+	decode_text(s, true).map(Self::from_bytes)
+    }
+}
+
+impl IV
+{
+    /// Parse an IV of any length, zero-padding or truncating it to `IV_SIZE` bytes.
+    #[inline] pub fn parse_lenient(s: &str) -> Result<Self, KeyParseError>
+    {
+	decode_text(s, true).map(Self)
+    }
+}
+
+impl XNonce
+{
+    /// Parse a nonce of any length, zero-padding or truncating it to `XNONCE_SIZE` bytes.
+    #[inline] pub fn parse_lenient(s: &str) -> Result<Self, KeyParseError>
+    {
+	decode_text(s, true).map(Self)
+    }
+}
+
 impl str::FromStr for Key
 {
-    type Err = base64::DecodeError;
+    type Err = KeyParseError;
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-	let mut buffer = Vec::with_capacity(KEY_SIZE);
-	base64::decode_config_buf(s.as_bytes(), base64::STANDARD, &mut buffer)?;
+    /// Parse a key of exactly `KEY_SIZE` bytes
+    #[inline] fn from_str(s: &str) -> Result<Self, Self::Err> {
+	decode_text(s, false).map(Self::from_bytes)
+    }
+}
+
+impl str::FromStr for Fingerprint
+{
+    type Err = KeyParseError;
 
-	let mut this = Self::default();
-	let sz = std::cmp::min(KEY_SIZE, buffer.len());
-	(&mut this.0[..sz]).copy_from_slice(&buffer[..sz]);
-	Ok(this)
+    /// Parse a full fingerprint, as it is displayed
+    #[inline] fn from_str(s: &str) -> Result<Self, Self::Err> {
+	decode_text(s, false).map(Self)
     }
 }
 
 impl str::FromStr for IV
 {
-    type Err = base64::DecodeError;
+    type Err = KeyParseError;
+
+    /// Parse an IV of exactly `IV_SIZE` bytes
+    #[inline] fn from_str(s: &str) -> Result<Self, Self::Err> {
+	decode_text(s, false).map(Self)
+    }
+}
+
+impl str::FromStr for XNonce
+{
+    type Err = KeyParseError;
+
+    /// Parse a nonce of exactly `XNONCE_SIZE` bytes
+    #[inline] fn from_str(s: &str) -> Result<Self, Self::Err> {
+	decode_text(s, false).map(Self)
+    }
+}
+
+/// An error parsing a key, IV or nonce
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyParseError
+{
+    /// The input is not valid base64
+    Base64(base64::DecodeError),
+    /// The input is not valid hex
+    Hex(HexDecodeError),
+    /// The input has a prefix that is not an `Encoding`'s
+    UnknownEncoding(String),
+    /// The input decodes to the wrong number of bytes
+    Length {
+	expected: usize,
+	actual: usize,
+    },
+    /// The input is neither raw bytes of the right length nor UTF-8 text
+    Utf8(str::Utf8Error),
+}
+
+impl error::Error for KeyParseError
+{
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+	match self {
+	    Self::Base64(e) => Some(e),
+	    Self::Hex(e) => Some(e),
+	    Self::Utf8(e) => Some(e),
+	    Self::UnknownEncoding(_) | Self::Length { .. } => None,
+	}
+    }
+}
+
+impl fmt::Display for KeyParseError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+	match self {
+	    Self::Base64(e) => write!(f, "invalid base64: {}", e),
+	    Self::Hex(e) => write!(f, "invalid hex: {}", e),
+	    Self::UnknownEncoding(prefix) => write!(f, "unknown encoding `{}:` (expected one of `hex:`, `b64:` or `b64url:`)", prefix),
+	    Self::Length { expected, actual } => write!(f, "expected {} bytes, but got {}", expected, actual),
+	    Self::Utf8(e) => write!(f, "not raw key bytes or text: {}", e),
+	}
+    }
+}
+
+impl From<base64::DecodeError> for KeyParseError
+{
+    #[inline] fn from(from: base64::DecodeError) -> Self
+    {
+	Self::Base64(from)
+    }
+}
+
+impl From<HexDecodeError> for KeyParseError
+{
+    #[inline] fn from(from: HexDecodeError) -> Self
+    {
+	Self::Hex(from)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-	let mut buffer = Vec::with_capacity(IV_SIZE);
-	base64::decode_config_buf(s.as_bytes(), base64::STANDARD, &mut buffer)?;
+    #[test]
+    fn key_parse()
+    {
+	let key = Key::new();
+	let raw: &[u8] = &key.expose_secret()[..];
+	assert_eq!(Key::decode(raw), Ok(key.clone()));
+	assert_eq!(Key::decode(format!("{}\n", key.encode(Encoding::Hex).as_str()).as_bytes()), Ok(key.clone()));
+	assert_eq!(Key::decode(format!("  {}\n", base64::encode(raw)).as_bytes()), Ok(key.clone()));
+	assert!(matches!(Key::decode(b"not a key!"), Err(KeyParseError::Base64(_))));
+	assert!(matches!(Key::decode(b"\xff not text"), Err(KeyParseError::Utf8(_))));
+	// Short hex is not zero-padded
+	assert_eq!(Key::decode(format!("hex:{}", &key.encode(Encoding::Hex)[2..]).as_bytes()), Err(KeyParseError::Length { expected: 32, actual: 31 }));
+
+	// `encode()` and `FromStr` are symmetric, and every encoding can be given with or without its prefix
+	for encoding in Encoding::ALL {
+	    let text = key.encode(encoding);
+	    assert_eq!(text.parse::<Key>(), Ok(key.clone()), "{}", encoding);
+	    assert_eq!(format!("{}:{}", encoding, text.as_str()).parse::<Key>(), Ok(key.clone()), "{}", encoding);
+	}
+	assert_eq!("B64URL:AA".parse::<IV>(), Err(KeyParseError::Length { expected: 12, actual: 1 }));
+	assert_eq!("b32:AAAA".parse::<Key>(), Err(KeyParseError::UnknownEncoding("b32".to_owned())));
 
-	let mut this = Self::default();
-	let sz = std::cmp::min(IV_SIZE, buffer.len());
-	(&mut this.0[..sz]).copy_from_slice(&buffer[..sz]);
-	Ok(this)
+	// Keys and IVs of the wrong length are only accepted when parsing leniently
+	let short = base64::encode(&raw[..16]);
+	assert_eq!(short.parse::<Key>(), Err(KeyParseError::Length { expected: 32, actual: 16 }));
+	assert_eq!(base64::encode([raw, raw].concat()).parse::<Key>(), Err(KeyParseError::Length { expected: 32, actual: 64 }));
+	let padded = Key::parse_lenient(&short).unwrap();
+	let padded: &[u8] = &padded.expose_secret()[..];
+	assert_eq!(padded[..16], raw[..16]);
+	assert_eq!(padded[16..], [0u8; 16]);
+	assert_eq!(base64::encode(raw).parse::<Key>(), Ok(key.clone()));
+	assert_eq!(crate::cha::CipherSuite::XChaCha20Poly1305.parse_nonce(&base64::encode(&raw[..12]), false), Err(KeyParseError::Length { expected: 24, actual: 12 }));
+	assert!(crate::cha::CipherSuite::XChaCha20Poly1305.parse_nonce(&base64::encode(&raw[..12]), true).is_ok());
+    }
+
+    #[test]
+    fn key_secret()
+    {
+	let key = Key::new();
+	let redacted = format!("{:?}", crate::cha::KeySource::from(key.clone()));
+	assert!(!redacted.contains(key.encode(Encoding::Hex).as_str()) && !redacted.contains(key.encode(Encoding::Base64).as_str()), "{}", redacted);
+	assert!(redacted.contains(&key.fingerprint().short()), "{}", redacted);
+	// Displaying a key only shows its fingerprint
+	assert_eq!(key.to_string(), key.fingerprint().short());
+	assert!(key.fingerprint().to_string().starts_with(&key.to_string()));
+	assert_eq!(key.fingerprint().to_string().parse(), Ok(key.fingerprint()));
+	assert_ne!(key.fingerprint(), Key::new().fingerprint());
+	let redacted = format!("{:?}", crate::cha::KeySource::Passphrase(b"hunter2".to_vec().into(), None));
+	assert!(!redacted.contains("hunter2"), "{}", redacted);
+
+	let mut other = key.clone();
+	assert_eq!(key, other);
+	other.expose_secret_mut()[KEY_SIZE-1] ^= 1;
+	assert_ne!(key, other);
+    }
+
+    #[test]
+    fn derive()
+    {
+	let master = Key::new();
+	let key = master.derive(b"service", b"salt");
+	assert_eq!(key, master.derive(b"service", b"salt"));
+	for other in [master.derive(b"service", b""), master.derive(b"other", b"salt"), Key::new().derive(b"service", b"salt")] {
+	    assert_ne!(key, other);
+	}
+	// Plain HKDF-SHA256, with the label as the info
+	let mut okm = [0u8; KEY_SIZE];
+	crate::kdf::hkdf(&master.expose_secret()[..], b"salt", b"service", &mut okm).unwrap();
+	assert_eq!(key.expose_secret(), &okm);
     }
 }
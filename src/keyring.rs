@@ -0,0 +1,253 @@
+//! A directory of named keys
+//!
+//! Each key is stored in its own file, `<name>.key`, as base64 (the same as `keygen --out` writes it,) readable only by the user.
+//! Keys are looked up by name, or by (a prefix of) their fingerprint (see `key::Key::fingerprint()`.)
+use super::*;
+use key::Fingerprint;
+
+use std::{
+    fmt, error,
+    io::{self, Write},
+    fs,
+    path::{Path, PathBuf},
+};
+use zeroize::Zeroizing;
+
+/// The environment variable the keyring directory is read from, if `--keyring` is not given
+pub const DIR_ENV: &str = "CHACHA20_KEYRING";
+/// The extension of the files keys are stored in
+pub const EXTENSION: &str = "key";
+/// The shortest prefix of a fingerprint a key can be looked up by
+pub const MIN_PREFIX: usize = 8;
+
+/// A key in the keyring
+#[derive(Debug, Clone)]
+pub struct Entry
+{
+    pub name: String,
+    pub key: Key,
+}
+
+impl Entry
+{
+    #[inline] pub fn fingerprint(&self) -> Fingerprint
+    {
+	self.key.fingerprint()
+    }
+}
+
+/// A directory of named keys
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Keyring
+{
+    dir: PathBuf,
+}
+
+impl Keyring
+{
+    /// The keyring in `dir`, which is created when a key is first added to it
+    #[inline] pub fn new(dir: impl Into<PathBuf>) -> Self
+    {
+	Self { dir: dir.into() }
+    }
+
+    /// The keyring in `$CHACHA20_KEYRING`, or else `$XDG_DATA_HOME/chacha20/keys` (`~/.local/share/chacha20/keys` by default.)
+    pub fn from_env() -> Option<Self>
+    {
+	use std::env::var_os;
+	if let Some(dir) = var_os(DIR_ENV).filter(|dir| !dir.is_empty()) {
+	    return Some(Self::new(dir));
+	}
+	let data = var_os("XDG_DATA_HOME").filter(|dir| !dir.is_empty()).map(PathBuf::from)
+	    .or_else(|| var_os("HOME").filter(|dir| !dir.is_empty()).map(|home| Path::new(&home).join(".local/share")))?;
+	Some(Self::new(data.join(env!("CARGO_PKG_NAME")).join("keys")))
+    }
+
+    #[inline] pub fn dir(&self) -> &Path
+    {
+	&self.dir
+    }
+
+    fn path(&self, name: &str) -> PathBuf
+    {
+	self.dir.join(format!("{}.{}", name, EXTENSION))
+    }
+
+    /// All the keys in the keyring, sorted by name. A keyring whose directory does not exist is empty.
+    pub fn entries(&self) -> Result<Vec<Entry>, Error>
+    {
+	let dir = match fs::read_dir(&self.dir) {
+	    Ok(dir) => dir,
+	    Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+	    Err(err) => return Err(Error::Io(self.dir.clone(), err)),
+	};
+	let mut entries = Vec::new();
+	for file in dir {
+	    let path = file.map_err(|e| Error::Io(self.dir.clone(), e))?.path();
+	    let Some(name) = path.file_stem().and_then(|name| name.to_str()).filter(|_| path.extension().is_some_and(|ext| ext == EXTENSION)) else {
+		continue;
+	    };
+	    if check_name(name).is_err() {
+		continue;
+	    }
+	    let data = Zeroizing::new(fs::read(&path).map_err(|e| Error::Io(path.clone(), e))?);
+	    let key = Key::decode(&data[..]).map_err(|e| Error::Key(name.to_owned(), e))?;
+	    entries.push(Entry { name: name.to_owned(), key });
+	}
+	entries.sort_by(|a, b| a.name.cmp(&b.name));
+	Ok(entries)
+    }
+
+    /// Find the key named `id`, or else the one key whose fingerprint starts with `id` (which must be at least `MIN_PREFIX` hex digits.)
+    pub fn find(&self, id: &str) -> Result<Entry, Error>
+    {
+	let entries = self.entries()?;
+	if let Some(entry) = entries.iter().find(|entry| entry.name == id) {
+	    return Ok(entry.clone());
+	}
+	if id.len() < MIN_PREFIX {
+	    return Err(Error::NotFound(id.to_owned()));
+	}
+	let mut found = entries.into_iter().filter(|entry| entry.fingerprint().matches(id));
+	match (found.next(), found.next()) {
+	    (Some(entry), None) => Ok(entry),
+	    (Some(_), Some(_)) => Err(Error::Ambiguous(id.to_owned())),
+	    (None, _) => Err(Error::NotFound(id.to_owned())),
+	}
+    }
+
+    /// Add `key` to the keyring as `name`.
+    ///
+    /// Fails if there is already a key with that name, or the same key under another name.
+    pub fn add(&self, name: &str, key: &Key) -> Result<(), Error>
+    {
+	check_name(name)?;
+	if let Some(entry) = self.entries()?.into_iter().find(|entry| entry.key == *key) {
+	    return Err(Error::Exists(entry.name));
+	}
+	{
+	    use std::os::unix::fs::DirBuilderExt;
+	    fs::DirBuilder::new()
+		.recursive(true)
+		.mode(0o700)
+		.create(&self.dir)
+		.map_err(|e| Error::Io(self.dir.clone(), e))?;
+	}
+	use std::os::unix::fs::OpenOptionsExt;
+	let path = self.path(name);
+	let mut file = match fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path) {
+	    Ok(file) => file,
+	    Err(err) if err.kind() == io::ErrorKind::AlreadyExists => return Err(Error::Exists(name.to_owned())),
+	    Err(err) => return Err(Error::Io(path, err)),
+	};
+	writeln!(file, "{}", key.encode(key::Encoding::Base64).as_str())
+	    .and_then(|_| file.sync_all())
+	    .map_err(|e| Error::Io(path, e))
+    }
+
+    /// Remove the key `id` refers to (see `find()`), returning it.
+    pub fn remove(&self, id: &str) -> Result<Entry, Error>
+    {
+	let entry = self.find(id)?;
+	let path = self.path(&entry.name);
+	fs::remove_file(&path).map_err(|e| Error::Io(path, e))?;
+	Ok(entry)
+    }
+}
+
+/// Check that `name` can be used as the name of a key: it is not empty, does not start with `.`, and only contains ASCII letters, digits, `.`, `_` and `-`.
+fn check_name(name: &str) -> Result<(), Error>
+{
+    if name.is_empty() || name.starts_with('.') || !name.bytes().all(|b| b.is_ascii_alphanumeric() || b"._-".contains(&b)) {
+	return Err(Error::InvalidName(name.to_owned()));
+    }
+    Ok(())
+}
+
+/// An error reading or changing a keyring
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error
+{
+    /// The keyring directory or a key file could not be read or written
+    Io(PathBuf, io::Error),
+    /// A key file does not contain a key
+    Key(String, key::KeyParseError),
+    /// There is no key with this name or fingerprint
+    NotFound(String),
+    /// More than one key's fingerprint starts with this prefix
+    Ambiguous(String),
+    /// There is already a key with this name (or the key is already in the keyring under this name)
+    Exists(String),
+    /// This name cannot be used for a key
+    InvalidName(String),
+}
+
+impl error::Error for Error
+{
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+	Some(match self {
+	    Self::Io(_, io) => io,
+	    Self::Key(_, err) => err,
+	    _ => return None,
+	})
+    }
+}
+
+impl fmt::Display for Error
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+	match self {
+	    Self::Io(path, io) => write!(f, "keyring: `{}`: {}", path.display(), io),
+	    Self::Key(name, err) => write!(f, "keyring: the file of key `{}` does not contain a key: {}", name, err),
+	    Self::NotFound(id) => write!(f, "keyring: there is no key named `{}` or with a fingerprint starting with it", id),
+	    Self::Ambiguous(id) => write!(f, "keyring: more than one key has a fingerprint starting with `{}`", id),
+	    Self::Exists(name) => write!(f, "keyring: the key `{}` already exists", name),
+	    Self::InvalidName(name) => write!(f, "keyring: invalid key name `{}` (it may only contain letters, digits, `.`, `_` and `-`, and not start with `.`)", name),
+	}
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn keyring()
+    {
+	let dir = TempDir::new("keyring");
+	// Created when the first key is added
+	let keyring = Keyring::new(dir.join("keys"));
+	assert!(keyring.entries().unwrap().is_empty());
+	let (a, b) = (Key::new(), Key::new());
+	keyring.add("a", &a).unwrap();
+	keyring.add("b.2", &b).unwrap();
+	assert!(matches!(keyring.add("a", &Key::new()), Err(Error::Exists(name)) if name == "a"));
+	assert!(matches!(keyring.add("c", &b), Err(Error::Exists(name)) if name == "b.2"));
+	assert!(matches!(keyring.add("../c", &Key::new()), Err(Error::InvalidName(_))));
+
+	assert_eq!(keyring.entries().unwrap().iter().map(|e| (&e.name[..], e.fingerprint())).collect::<Vec<_>>(), [("a", a.fingerprint()), ("b.2", b.fingerprint())]);
+	assert_eq!(keyring.find(&b.fingerprint().to_string()[..MIN_PREFIX].to_uppercase()).unwrap().key, b);
+	assert!(matches!(keyring.find(&a.fingerprint().to_string()[..MIN_PREFIX-1]), Err(Error::NotFound(_))));
+
+	// The header records which key the ciphertext was encrypted with
+	let op = |direction, key: cha::KeySource, nonce| cha::Operation::with_suite(direction, cha::CipherSuite::default(), key, nonce).with_header(true);
+	let mut stream = stream::Sink::new(Vec::new(), op(cha::Direction::Encrypt, b.clone().into(), Some(cha::CipherSuite::default().new_nonce())).with_key_id(true)).unwrap();
+	stream.write_all(b"Hello world!").unwrap();
+	let enc = stream.finish().unwrap();
+	assert_eq!(header::Header::parse(&enc[..]).unwrap().unwrap().0.key_id(), Some(b.fingerprint()));
+	let dec = |key| -> io::Result<Vec<u8>> {
+	    let mut stream = stream::Sink::new(Vec::new(), op(cha::Direction::Decrypt, key, None))?;
+	    stream.write_all(&enc[..])?;
+	    stream.finish()
+	};
+	let all = cha::KeySource::Recipients(keyring.entries().unwrap().into_iter().map(|e| e.key.into()).collect());
+	assert_eq!(&dec(all).unwrap()[..], b"Hello world!");
+	dec(a.clone().into()).expect_err("ciphertext was decrypted with a key other than the one in its header");
+
+	assert_eq!(keyring.remove("a").unwrap().key, a);
+	assert!(matches!(keyring.find("a"), Err(Error::NotFound(_))));
+    }
+}
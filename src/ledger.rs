@@ -0,0 +1,175 @@
+//! A persistent record of the nonces each key has been used with, to catch a (key, nonce) pair being reused
+//!
+//! There is one file per key in the ledger directory, named after the key's fingerprint. It holds a `RECORD_SIZE` byte hash of each nonce the key has encrypted with (not the nonces themselves.)
+//! The file is locked (with `flock()`) while it is checked and appended to, so that processes encrypting at the same time cannot both use the same nonce.
+use super::*;
+use key::{Fingerprint, Nonce};
+
+use std::{
+    fmt, error,
+    io::{self, Read, Write},
+    fs,
+    path::PathBuf,
+};
+
+/// The environment variable that enables the ledger, in this directory, if `--nonce-ledger` is not given
+pub const DIR_ENV: &str = "CHACHA20_NONCE_LEDGER";
+/// The size of the hash of each nonce in a ledger file
+pub const RECORD_SIZE: usize = 16;
+
+/// What to do when a nonce is reused
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Policy
+{
+    /// Fail without encrypting anything
+    #[default]
+    Refuse,
+    /// Print a warning, and encrypt anyway
+    Warn,
+}
+
+impl Policy
+{
+    /// The policy with this name
+    pub fn from_name(name: &str) -> Option<Self>
+    {
+	match name {
+	    "refuse" => Some(Self::Refuse),
+	    "warn" => Some(Self::Warn),
+	    _ => None,
+	}
+    }
+}
+
+/// A directory of the nonces used with each key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ledger
+{
+    dir: PathBuf,
+}
+
+impl Ledger
+{
+    /// The ledger in `dir`, which is created when a nonce is first recorded in it
+    #[inline] pub fn new(dir: impl Into<PathBuf>) -> Self
+    {
+	Self { dir: dir.into() }
+    }
+
+    /// The ledger in `$CHACHA20_NONCE_LEDGER`, if it is set
+    pub fn from_env() -> Option<Self>
+    {
+	std::env::var_os(DIR_ENV).filter(|dir| !dir.is_empty()).map(Self::new)
+    }
+
+    /// The hash recorded for `nonce` in the file of the key with `fingerprint`
+    fn record_of(fingerprint: &Fingerprint, nonce: &Nonce) -> [u8; RECORD_SIZE]
+    {
+	let mut hasher = openssl::sha::Sha256::new();
+	hasher.update(b"chacha20 nonce ledger");
+	hasher.update(fingerprint.as_ref());
+	hasher.update(nonce.as_ref());
+	hasher.finish()[..RECORD_SIZE].try_into().unwrap()
+    }
+
+    /// Record that `key` is used with `nonce`.
+    ///
+    /// Returns `Error::Reused` (without recording anything) if it has been used with `nonce` before.
+    pub fn record(&self, key: &Key, nonce: &Nonce) -> Result<(), Error>
+    {
+	use std::os::unix::{
+	    fs::{DirBuilderExt, OpenOptionsExt},
+	    io::AsRawFd,
+	};
+	let fingerprint = key.fingerprint();
+	let record = Self::record_of(&fingerprint, nonce);
+	fs::DirBuilder::new()
+	    .recursive(true)
+	    .mode(0o700)
+	    .create(&self.dir)
+	    .map_err(|e| Error::Io(self.dir.clone(), e))?;
+	let path = self.dir.join(fingerprint.to_string());
+	let err = |e| Error::Io(path.clone(), e);
+	let mut file = fs::OpenOptions::new()
+	    .read(true)
+	    .append(true)
+	    .create(true)
+	    .mode(0o600)
+	    .open(&path)
+	    .map_err(err)?;
+	// Held until the file is closed
+	if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+	    return Err(err(io::Error::last_os_error()));
+	}
+	let mut records = Vec::new();
+	file.read_to_end(&mut records).map_err(err)?;
+	// A record cut short by a crash is ignored, and completed by the next one
+	if records.chunks_exact(RECORD_SIZE).any(|r| r == record) {
+	    return Err(Error::Reused(fingerprint));
+	}
+	let padding = (RECORD_SIZE - records.len() % RECORD_SIZE) % RECORD_SIZE;
+	file.write_all(&[&[0u8; RECORD_SIZE][..padding], &record[..]].concat())
+	    .and_then(|_| file.sync_data())
+	    .map_err(err)
+    }
+}
+
+/// An error checking or recording a nonce
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error
+{
+    /// The ledger directory or a key's file could not be read or written
+    Io(PathBuf, io::Error),
+    /// The key with this fingerprint has already been used with the nonce
+    Reused(Fingerprint),
+}
+
+impl error::Error for Error
+{
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+	match self {
+	    Self::Io(_, io) => Some(io),
+	    _ => None,
+	}
+    }
+}
+
+impl fmt::Display for Error
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+	match self {
+	    Self::Io(path, io) => write!(f, "nonce ledger: `{}`: {}", path.display(), io),
+	    Self::Reused(fingerprint) => write!(f, "nonce ledger: the IV has already been used with key {}, reusing it would break the encryption of both ciphertexts", fingerprint.short()),
+	}
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn nonce_ledger()
+    {
+	let dir = TempDir::new("ledger");
+	// Created when the first nonce is recorded
+	let ledger = Ledger::new(dir.join("ledger"));
+	let (key, iv) = cha::keygen();
+	let nonce = Nonce::from(iv);
+	ledger.record(&key, &nonce).unwrap();
+	assert!(matches!(ledger.record(&key, &nonce), Err(Error::Reused(fp)) if fp == key.fingerprint()));
+	ledger.record(&key, &key::IV::new().into()).unwrap();
+	ledger.record(&Key::new(), &nonce).unwrap();
+
+	// Only one of several racing to use the same nonce succeeds
+	let nonce = Nonce::from(key::IV::new());
+	let used = std::thread::scope(|s| {
+	    let threads: Vec<_> = (0..8).map(|_| s.spawn(|| ledger.record(&key, &nonce).is_ok())).collect();
+	    threads.into_iter().map(|t| t.join().unwrap()).filter(|&ok| ok).count()
+	});
+	assert_eq!(used, 1);
+    }
+}
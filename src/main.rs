@@ -4,114 +4,300 @@
 
 #![allow(dead_code)]
 
-#[macro_use] extern crate lazy_static;
+extern crate lazy_static;
 //extern crate test;
 
 #[macro_use] mod ext; #[allow(unused_imports)] use ext::*;
 
 mod key;
 mod cha;
+mod kdf;
+mod recipient;
+mod keyring;
+mod ledger;
+mod sequence;
+mod header;
 mod stream;
+mod args;
+mod secure;
 
-use key::{Key, IV};
+use key::Key;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Mode
 {
-    Encrypt, Decrypt, Keygen
+    Encrypt, Decrypt, Verify, Keygen, KdfBench, Rewrap, Keys, Derive
 }
 
-fn keys() -> Result<(Mode, Key, IV), base64::DecodeError>
+impl Mode
 {
-    let mut args = std::env::args();
-    let prog_name = args.next().unwrap();
+    /// The name of this mode's command
+    #[inline] pub fn name(&self) -> &'static str
+    {
+	match self {
+	    Self::Encrypt => "encrypt",
+	    Self::Decrypt => "decrypt",
+	    Self::Verify => "verify",
+	    Self::Keygen => "keygen",
+	    Self::KdfBench => "kdf-bench",
+	    Self::Rewrap => "rewrap",
+	    Self::Keys => "keys",
+	    Self::Derive => "derive",
+	}
+    }
 
-    let mode = match args.next()
-	.map(|x| x.chars().next().map(|x| x.to_ascii_lowercase()))
-	.flatten()
+    /// The direction of the cipher operation this mode performs, if any
+    #[inline] pub fn direction(&self) -> Option<cha::Direction>
     {
-	Some('e') => Mode::Encrypt,
-	Some('d') => Mode::Decrypt,
-	Some('k') => Mode::Keygen,
-	other => {
-	    eprintln!("{} (v{}) - chacha20_poly1305 command line encryption tool",
-		      env!("CARGO_PKG_NAME"),
-		      env!("CARGO_PKG_VERSION"));
-	    eprintln!(" by {} with <3 (licensed GPL v3.0 or later)", env!("CARGO_PKG_AUTHORS"));
-	    eprintln!("\nStreams stdin to stdout through a chacha20_poly1305 cipher.");
-	    eprintln!();
-	    eprintln!("Usage: {} encrypt [<base64 key>] [<base64 iv>]", prog_name);
-	    eprintln!("Usage: {} decrypt [<base64 key>] [<base64 iv>]", prog_name);
-	    eprintln!("Usage: {} keygen [<base64 key>] [<base64 iv>]", prog_name);
-	    eprintln!("Usage: {} help", prog_name);
-	    eprintln!();
-	    eprintln!("(Key size is {}, IV size is {})", cha::KEY_SIZE, cha::IV_SIZE);
-	    eprintln!("(requires OpenSSL 1.1.0 or newer)");
-	    eprintln!("\nencrypt/decrypt:\n\tIf a key and/or IV are not provided, they are generated randomly and printed to stderr in order on one line each.");
-	    eprintln!("\tIf the key and/or IV provided's size is lower than the cipher's key/IV size, the rest of the key/IV is padded with 0s. If the size is higher, the extra bytes are ignored.");
-	    eprintln!("\nkeygen:\n\tThe key/iv is printed in the same way as auto-generated keys for the en/decryption modes, but to stdout instead of stderr. If a key is given as parameter, the key is not printed. If the iv is given as a parameter also, nothing is printed.");
-	    eprintln!("\nhelp:\n\tPrint this message to stderr then exit with code 0");
-	    std::process::exit(if other == Some('h') {0} else {1})
+	match self {
+	    Self::Encrypt => Some(cha::Direction::Encrypt),
+	    Self::Decrypt | Self::Verify => Some(cha::Direction::Decrypt),
+	    Self::Keygen | Self::KdfBench | Self::Rewrap | Self::Keys | Self::Derive => None,
 	}
-    };
+    }
+}
+
+const USE_MMAP: bool = cfg!(feature="mmap");
+
+#[cfg(feature="mmap")]
+mod mapped;
+
+/// Exit code for when the ciphertext fails authentication
+const EXIT_AUTH_FAILURE: i32 = 2;
+
+#[allow(unreachable_code)]
+fn try_mmap(op: cha::Operation) -> Result<Option<cha::Tag>, mapped::ProcessError>
+{
+    #[cfg(feature="mmap")] return mapped::try_process(op).map(|(_, tag)| tag);
+    
+    unreachable!("Built without feature `mmap`, but still tried to call into it. This is a bug")
+}
+
+#[allow(unreachable_code)]
+fn try_mmap_verify(op: &cha::Operation) -> Result<(), mapped::ProcessError>
+{
+    #[cfg(feature="mmap")] return mapped::try_verify(op).map(|_| ());
     
-    let key = match args.next() {
-	Some(key) => key.parse()?,
-	None => {
-	    let key = Key::new();
-	    if mode == Mode::Keygen {
-		println!("{}", base64::encode(&key));
-	    } else {
-		eprintln!("{}", base64::encode(&key));
+    unreachable!("Built without feature `mmap`, but still tried to call into it. This is a bug")
+}
+
+#[allow(unreachable_code)]
+fn try_mmap_range(op: &cha::Operation, offset: usize, length: Option<usize>) -> Result<(), mapped::ProcessError>
+{
+    #[cfg(feature="mmap")] return mapped::try_process_range(op, offset, length).map(|_| ());
+    
+    unreachable!("Built without feature `mmap`, but still tried to call into it. This is a bug")
+}
+
+#[allow(unreachable_code)]
+fn try_mmap_rewrite(file: &std::fs::File, rewrite: impl FnOnce(&header::Header) -> Result<header::Header, header::Error>) -> Result<(), mapped::ProcessError>
+{
+    #[cfg(feature="mmap")] return mapped::rewrite_header(file, rewrite).map(|_| ());
+    
+    unreachable!("Built without feature `mmap`, but still tried to call into it. This is a bug")
+}
+
+/// Change the recipients of the ciphertext in the file at `path` by rewriting its header in place, then exit.
+///
+/// The file key is unwrapped with one of the sources of `key`.
+fn rewrap(path: &str, key: &cha::KeySource, changes: &recipient::Rewrap) -> !
+{
+    let file = std::fs::OpenOptions::new().read(true).write(true).open(path).unwrap_or_else(|e| fail(e));
+    let rewrite = |header: &header::Header| changes.apply(header, key.sources());
+    if USE_MMAP {
+	match try_mmap_rewrite(&file, rewrite) {
+	    Ok(()) => std::process::exit(0),
+	    Err(err) if err.is_rejection() => {
+		eprintln!("{}: {}", env!("CARGO_PKG_NAME"), err);
+		std::process::exit(1)
+	    },
+	    Err(err) => if cfg!(debug_assertions) {
+		eprintln!("Failed to mmap the header for rewriting, falling back to reading it: {}", &err);
 	    }
-	    key
+	}
+    }
+
+    // Only the header is read and written
+    use std::os::unix::fs::FileExt;
+    let size = file.metadata().map(|meta| meta.len()).unwrap_or_else(|e| fail(e));
+    let mut input = vec![0u8; std::cmp::min(size, header::MAX_SIZE as u64) as usize];
+    file.read_exact_at(&mut input[..], 0).unwrap_or_else(|e| fail(e));
+    let header = header::rewrite(&input[..], rewrite).unwrap_or_else(|e| fail(e.into()));
+    file.write_all_at(&header[..], 0)
+	.and_then(|_| file.sync_data())
+	.unwrap_or_else(|e| fail(e));
+    std::process::exit(0)
+}
+
+/// Record the key and IV of an encryption in the nonce ledger, exiting if they have been used together before (unless the policy is to warn.)
+///
+/// Keys derived from a passphrase (with a new salt) and file keys are only ever used once, so they are not recorded.
+fn check_nonce(options: &args::Options)
+{
+    let (Some((ledger, policy)), cha::KeySource::Key(key), Some(nonce)) = (&options.ledger, &options.key, &options.nonce) else {
+	return;
+    };
+    match ledger.record(key, nonce) {
+	Ok(()) => (),
+	Err(err @ ledger::Error::Reused(_)) if *policy == ledger::Policy::Warn => eprintln!("{}: WARNING: {}", env!("CARGO_PKG_NAME"), err),
+	Err(err) => {
+	    eprintln!("{}: {}", env!("CARGO_PKG_NAME"), err);
+	    std::process::exit(1)
 	},
+    }
+}
+
+/// Run a `keys` command on the keyring, then exit.
+fn keys(keyring: &keyring::Keyring, command: &args::KeysCommand) -> !
+{
+    let fail = |err: keyring::Error| -> ! {
+	eprintln!("{}: {}", env!("CARGO_PKG_NAME"), err);
+	std::process::exit(1)
     };
-    let iv = match args.next() {
-	Some(iv) => iv.parse()?,
-	None => {
-	    let iv = IV::new();
-	    if mode == Mode::Keygen {
-		println!("{}", base64::encode(&iv));
-	    } else {
-		eprintln!("{}", base64::encode(&iv));
+    match command {
+	args::KeysCommand::List => for entry in keyring.entries().unwrap_or_else(|e| fail(e)) {
+	    println!("{}  {}", entry.fingerprint(), entry.name);
+	},
+	args::KeysCommand::Add(name, key) => {
+	    keyring.add(name, key).unwrap_or_else(|e| fail(e));
+	    println!("{}", key.fingerprint());
+	},
+	args::KeysCommand::Remove(id) => {
+	    let entry = keyring.remove(id).unwrap_or_else(|e| fail(e));
+	    eprintln!("Removed `{}` ({})", entry.name, entry.fingerprint());
+	},
+	args::KeysCommand::Export(id, format, out) => {
+	    let entry = keyring.find(id).unwrap_or_else(|e| fail(e));
+	    match out {
+		Some(path) => args::write_key_file(path, &entry.key, *format).unwrap_or_else(|e| fail(keyring::Error::Io(path.into(), e))),
+		None => println!("{}", entry.key.encode(*format).as_str()),
 	    }
-	    iv
 	},
-    };
+    }
+    std::process::exit(0)
+}
+
+/// Decrypt only a range of the plaintext of stdin to stdout, then exit.
+fn decrypt_range(op: cha::Operation, offset: usize, length: Option<usize>) -> !
+{
+    if USE_MMAP {
+	match try_mmap_range(&op, offset, length) {
+	    Ok(()) => std::process::exit(0),
+	    Err(err) if err.is_rejection() => {
+		eprintln!("{}: {}", env!("CARGO_PKG_NAME"), err);
+		std::process::exit(if err.is_authentication() { EXIT_AUTH_FAILURE } else { 1 })
+	    },
+	    Err(err) => if cfg!(debug_assertions) {
+		eprintln!("Failed to mmap input for random access, falling back to reading it: {}", &err);
+	    }
+	}
+    }
 
-    Ok((mode, key, iv))
+    // The input cannot be seeked, so all of it is read
+    let mut input = Vec::new();
+    std::io::Read::read_to_end(&mut std::io::stdin().lock(), &mut input).unwrap_or_else(|e| fail(e));
+    let mut stdout = std::io::stdout().lock();
+    op.process_range(&input[..], offset, length, &mut stdout)
+	.and_then(|_| std::io::Write::flush(&mut stdout))
+	.unwrap_or_else(|e| fail(e));
+    std::process::exit(0)
 }
 
-const USE_MMAP: bool = if cfg!(feature="mmap") {
-    true
-} else {
-    false
-};
+/// Check the authenticity of stdin without writing any plaintext, then exit.
+fn verify(op: cha::Operation) -> !
+{
+    if USE_MMAP {
+	match try_mmap_verify(&op) {
+	    Ok(()) => std::process::exit(0),
+	    Err(err) if err.is_rejection() => {
+		eprintln!("{}: {}", env!("CARGO_PKG_NAME"), err);
+		std::process::exit(if err.is_authentication() { EXIT_AUTH_FAILURE } else { 1 })
+	    },
+	    Err(err) => if cfg!(debug_assertions) {
+		eprintln!("Failed to mmap input for verifying, falling back to stream: {}", &err);
+	    }
+	}
+    }
 
-#[cfg(feature="mmap")]
-mod mapped;
+    // Streaming, the plaintext is discarded
+    let mut output = stream::Sink::new(std::io::sink(), op).unwrap_or_else(|e| fail(e));
+    std::io::copy(&mut std::io::stdin().lock(), &mut output).unwrap_or_else(|e| fail(e));
+    output.finish().unwrap_or_else(|e| fail(e));
+    std::process::exit(0)
+}
 
-#[allow(unreachable_code)]
-fn try_mmap(decrypt: bool, key: Key, iv: IV) -> Result<i32, mapped::ProcessError>
+/// Print this processing error and exit, with `EXIT_AUTH_FAILURE` if it is an authentication failure.
+fn fail(err: std::io::Error) -> !
 {
-    #[cfg(feature="mmap")] return mapped::try_process(if decrypt {
-	cha::decrypter(key, iv).expect("Failed to create decrypter")
+    eprintln!("{}: {}", env!("CARGO_PKG_NAME"), err);
+    std::process::exit(if cha::AuthenticationError::is_cause_of(&err) {
+	EXIT_AUTH_FAILURE
     } else {
-	cha::encrypter(key, iv).expect("Failed to create encrypter")
-    }).map(|_| 0i32);
-    
-    unreachable!("Built without feature `mmap`, but still tried to call into it. This is a bug")
+	1
+    })
+}
+
+/// Calibrate the parameters of a KDF, then print them as an `--kdf` option to stdout.
+fn kdf_bench(algorithm: kdf::Algorithm, target: std::time::Duration, max_memory: u64) -> !
+{
+    match kdf::Kdf::calibrate(algorithm, target, max_memory) {
+	Ok((kdf, elapsed)) => {
+	    eprintln!("{}: {} takes {} ms and {} MiB to derive a key", env!("CARGO_PKG_NAME"), kdf, elapsed.as_millis(), kdf.memory().unwrap_or(0) >> 20);
+	    println!("--kdf {}", kdf);
+	    std::process::exit(0)
+	},
+	Err(err) => {
+	    eprintln!("{}: {}", env!("CARGO_PKG_NAME"), err);
+	    std::process::exit(1)
+	},
+    }
+}
+
+/// Output the tag of a detached encryption, if there is one.
+fn finish(options: &args::Options, tag: Option<cha::Tag>)
+{
+    if let Some(tag) = tag {
+	options.write_tag(&tag).unwrap_or_else(|e| fail(e));
+    }
 }
 
 fn main() {
-    let (mode, key, iv) = keys().expect("Failed to read keys from argv (base64)");
+    let options = match args::parse() {
+	Ok(options) => options,
+	Err(err) => {
+	    eprintln!("{}: {}", env!("CARGO_PKG_NAME"), err);
+	    std::process::exit(1)
+	},
+    };
+    if let Some((algorithm, target, max_memory)) = options.calibration {
+	kdf_bench(algorithm, target, max_memory);
+    }
+    if let Some((path, changes)) = &options.rewrap {
+	rewrap(path, &options.key, changes);
+    }
+    if let Some((keyring, command)) = &options.keys {
+	keys(keyring, command);
+    }
+    check_nonce(&options);
+    let Some(op) = options.operation() else {
+	// Keygen (or derive) has already printed the keys
+	return;
+    };
+    if options.mode == Mode::Verify {
+	verify(op);
+    }
+    if let Some((offset, length)) = options.range {
+	decrypt_range(op, offset, length);
+    }
     
     // Attempt a mapped solution
-    if USE_MMAP && mode != Mode::Keygen {
-	match try_mmap(mode == Mode::Decrypt, key, iv) {
-	    Ok(0) => return,
-	    Ok(n) => std::process::exit(n),
+    if USE_MMAP {
+	match try_mmap(op.clone()) {
+	    Ok(tag) => return finish(&options, tag),
+	    Err(err) if err.is_rejection() => {
+		eprintln!("{}: {}", env!("CARGO_PKG_NAME"), err);
+		std::process::exit(if err.is_authentication() { EXIT_AUTH_FAILURE } else { 1 })
+	    },
 	    Err(err) => if cfg!(debug_assertions) {
 		eprintln!("Failed to mmap input or output for processing, falling back to stream: {}", &err);
 		eprintln!("\t{:?}", err);
@@ -123,24 +309,8 @@ fn main() {
     let input = std::io::stdin();
 
     // Streaming
-    use std::io::Write;
-    match mode 
-    {
-	Mode::Encrypt => {
-	    
-	    let mut output = stream::Sink::encrypt(stdout.lock(), key, iv).expect("Failed to create encrypter");
-	    std::io::copy(&mut input.lock(), &mut output).expect("Failed to encrypt");
-	    output.flush().expect("Failed to flush stdout");
-	    
-	},
-	Mode::Decrypt => {
-	    let mut output = stream::Sink::decrypt(stdout.lock(), key, iv).expect("Failed to create decrypter");
-	    std::io::copy(&mut input.lock(), &mut output).expect("Failed to decrypt");
-	    output.flush().expect("Failed to flush stdout");
-	},
-	Mode::Keygen => {
-	    //println!("{}", base64::encode(&key));
-	    //println!("{}", base64::encode(&iv));
-	},
-    }
+    let mut output = stream::Sink::new(stdout.lock(), op).unwrap_or_else(|e| fail(e));
+    std::io::copy(&mut input.lock(), &mut output).unwrap_or_else(|e| fail(e));
+    let (_, tag) = output.finish_with_tag().unwrap_or_else(|e| fail(e));
+    finish(&options, tag);
 }
@@ -5,19 +5,12 @@ use std::os::unix::prelude::*;
 use std::{
     io,
     fs,
-    ptr,
     ops,
-    mem::{
-	self,
-	MaybeUninit,
-    },
-    borrow::{BorrowMut, Cow, Borrow},
-    convert::{TryFrom, TryInto,},
+    mem::MaybeUninit,
+    borrow::{BorrowMut, Borrow},
+    convert::TryInto,
     fmt, error,
 };
-use libc::{
-    mmap, munmap, madvise, MAP_FAILED,
-};
 use mapped_file::{
     MappedFile,
     Perm,
@@ -26,7 +19,6 @@ use mapped_file::{
 	MemoryFile,
     },
 };
-use openssl::symm::Crypter;
 /*
 #[derive(Debug)]
 struct MapInner
@@ -70,6 +62,33 @@ fn try_truncate(fd: &(impl AsRawFd + ?Sized), to: u64) -> io::Result<()>
 	_ => Err(io::Error::last_os_error()),
     }
 }
+/// The offset output written to `fd` will start at, if it is a regular file that is written at its end (so it can be cut off again with `try_truncate()`)
+fn append_offset(fd: &(impl AsRawFd + ?Sized)) -> Option<u64>
+{
+    let fd = fd.as_raw_fd();
+    let mut stat = MaybeUninit::uninit();
+    if unsafe { libc::fstat(fd, stat.as_mut_ptr()) } != 0 {
+	return None;
+    }
+    let stat = unsafe { stat.assume_init() };
+    if stat.st_mode & libc::S_IFMT != libc::S_IFREG {
+	return None;
+    }
+    let (flags, pos) = unsafe { (libc::fcntl(fd, libc::F_GETFL), libc::lseek(fd, 0, libc::SEEK_CUR)) };
+    (flags >= 0 && (flags & libc::O_APPEND != 0 || pos == stat.st_size)).then(|| stat.st_size.try_into().ok()).flatten()
+}
+
+/// Transform `input` into the mapped `output` with `op`.
+///
+/// If this fails (e.g. the ciphertext fails authentication), `output` is wiped and truncated, so that no unauthenticated plaintext is left in it.
+fn process_or_discard<V: AsRawFd>(op: &cha::Operation, input: &[u8], output: &mut MappedFile<V>) -> io::Result<(usize, Option<cha::Tag>)>
+{
+    op.process_with_tag(input, &mut output[..]).inspect_err(|_| {
+	output.fill(0);
+	let _ = try_truncate(Borrow::<V>::borrow(output), 0);
+    })
+}
+
 /*
 #[derive(Debug)]
 pub struct Mapped<T: ?Sized>
@@ -112,14 +131,6 @@ impl<T: AsRawFd> TryFrom<T> for Mapped<T>
 }*/
 
 
-fn process_mapped_files<T, U>(mode: &mut Crypter, input: &mut MappedFile<T>, output: &mut MappedFile<U>) -> io::Result<()>
-where T: AsRawFd,
-U: AsRawFd,
-{
-    mode.update(&input[..], &mut output[..])?;
-    Ok(())
-}
-
 fn try_map_sized<T: AsRawFd>(file: T, perm: mapped_file::Perm, flags: impl mapped_file::MapFlags) -> Result<MappedFile<T>, T>
 {
     macro_rules! unwrap {
@@ -163,17 +174,17 @@ fn translate_hugetlb_size(size: usize) -> mapped_file::hugetlb::HugePage
     #[inline] 
     fn check_func(sizes_kb: &[usize]) -> Option<&usize>
     {
-	sizes_kb.iter().skip_while(|&&kb| kb < 1024 * 1024).next()
-	    .or_else(|| sizes_kb.iter().nth(1)
+	sizes_kb.iter().find(|&&kb| kb >= 1024 * 1024)
+	    .or_else(|| sizes_kb.get(1)
 		     .or_else(|| sizes_kb.first()))
     }
     const MB: usize = 1024*1024;
+    const MB_1: usize = MB + 1;
     const GB: usize = 1024 * MB;
     
-    #[allow(overlapping_range_endpoints)]
     match size {
 	0..=MB => mapped_file::hugetlb::HugePage::Smallest,
-	MB..=GB => mapped_file::hugetlb::HugePage::Selected(check_func),
+	MB_1..=GB => mapped_file::hugetlb::HugePage::Selected(check_func),
 	_very_high => mapped_file::hugetlb::HugePage::Largest,
     }
 }
@@ -207,7 +218,6 @@ fn create_sized_temp_mapping_at<const HUGE: bool>(size: usize) -> io::Result<(Ma
 	    };
 	    return MappedFile::new(file, size, Perm::ReadWrite, Flags::Shared.with_hugetlb(hugetlb)).map(|x| (x, true));
 	},
-	0 | 
 	_ => MemoryFile::new(),
     }?;
     MappedFile::new(file, size, Perm::ReadWrite, Flags::Shared).map(|x| (x, false))
@@ -285,28 +295,27 @@ impl<T: io::Read+AsRawFd, U: io::Write+AsRawFd> OpTable<T, U>
 	Ok(())
     }
     /// Execute this en/decryption in an optimised function
-    pub fn execute(mut self, mut mode: impl BorrowMut<Crypter>) -> io::Result<usize>
+    ///
+    /// If the ciphertext fails authentication, any plaintext already written to a mapped output (or streamed to the end of an output file) is wiped before the error is returned.
+    /// Returns the number of bytes processed, and the tag if this is a detached encryption.
+    pub fn execute(mut self, op: &cha::Operation) -> io::Result<(usize, Option<cha::Tag>)>
     {
 	self.pre_process()?;
-	let mode: &mut Crypter = mode.borrow_mut();
 	match &mut self {
 	    Self::Both(input, output) => {
-		let len = std::cmp::min(input.len(), output.len());
-		process_mapped_files(mode, input, output)?;
+		let len = process_or_discard(op, &input[..], output)?;
 		
 		self.post_process()?;
 		Ok(len)
 	    },
 	    Self::Input(input, output, _) => {
-		let len = std::cmp::min(input.len(), output.len());
-		process_mapped_files(mode, input, output)?;
+		let len = process_or_discard(op, &input[..], output)?;
 
 		self.post_process()?;
 		Ok(len)
 	    },
 	    Self::Output(_, input, output) => {
-		let len = std::cmp::min(input.len(), output.len());
-		process_mapped_files(mode, input, output)?;
+		let len = process_or_discard(op, &input[..], output)?;
 
 		self.post_process()?;
 		Ok(len)
@@ -357,20 +366,35 @@ impl<T: io::Read+AsRawFd, U: io::Write+AsRawFd> OpTable<T, U>
 		}
 		
 		let mut _mem = try_allocmem!(BUFFER_SIZE);
-		let mut _memo = try_allocmem!(BUFFER_SIZE);
-		let mut buffer = try_allocmem!(_mem, BUFFER_SIZE);
-		let mut buffero = try_allocmem!(_memo, BUFFER_SIZE);
+		// Zeroed out and unlocked before the memory is unmapped or freed
+		let mut buffer = secure::Locked::new(try_allocmem!(_mem, BUFFER_SIZE));
 		
-		let mut read =0;
-		let mut cur;
-		while { cur = sin.read(&mut buffer[..])?; cur > 0 } {
-		    /*let cur =*/ mode.update(&buffer[..cur], &mut buffero[..cur])?;
-		    sout.write_all(&buffero[..cur])?;
-		    read += cur;
-		}
+		let start = append_offset(sout);
+		let (read, tag) = match (|| {
+		    let mut sink = stream::Sink::new(&mut *sout, op.clone())?;
+		    let mut read =0;
+		    let mut cur;
+		    while { cur = sin.read(&mut buffer[..])?; cur > 0 } {
+			io::Write::write_all(&mut sink, &buffer[..cur])?;
+			read += cur;
+		    }
+		    let (_, tag) = sink.finish_with_tag()?;
+		    io::Result::Ok((read, tag))
+		})() {
+		    Ok(done) => done,
+		    Err(err) => {
+			// Cut off the plaintext already streamed, if the output is a file that can be
+			if let Some(start) = start {
+			    let _ = sout.flush();
+			    let _ = try_truncate(sout, start);
+			    let _ = unsafe { libc::lseek(sout.as_raw_fd(), start as libc::off_t, libc::SEEK_SET) };
+			}
+			return Err(err);
+		    },
+		};
 
 		self.post_process()?;
-		Ok(read)
+		Ok((read, tag))
 	    },
 	}
     }
@@ -388,6 +412,14 @@ fn sized_then_or<T: AsRawFd, U, F>(stream: T, trans: F) -> Result<U, T>
 fn map_size_or<T: AsRawFd, U, F>(stream: T, size: usize, trans: F) -> Result<U, T>
 where F: FnOnce(MappedFile<T>, usize) -> U
 {
+    // If the file is grown but then cannot be mapped, shrink it back so it is not left padded with 0s.
+    let original_size = raw_file_size(&stream).ok();
+    let restore = |stream: T| {
+	if let Some(original_size) = original_size {
+	    let _ = try_truncate(&stream, original_size);
+	}
+	stream
+    };
     if try_map_to(&stream, size) {
 	// Sized
 	if cfg!(feature="unsafe-mappings") {
@@ -398,14 +430,14 @@ where F: FnOnce(MappedFile<T>, usize) -> U
 		if cfg!(debug_assertions) {
 		    eprintln!("Warning: Failed to re-open stdout: {}", err);
 		}
-		return Err(stream);
+		return Err(restore(stream));
 	    }
 	}
 	
 	// Then map read+write
 	match MappedFile::try_new(stream, size, Perm::ReadWrite, Flags::Shared) {
 	    Ok(map) => Ok(trans(map, size)),
-	    Err(e) => Err(e.into_inner()),
+	    Err(e) => Err(restore(e.into_inner())),
 	}
     } else {
 	// Unsized
@@ -419,6 +451,7 @@ pub enum ProcessErrorKind
 {
     Unknown,
     IO(io::Error),
+    Authentication(cha::AuthenticationError),
 }
 
 impl fmt::Display for ProcessErrorKind
@@ -428,6 +461,7 @@ impl fmt::Display for ProcessErrorKind
     {
 	match self {
 	    Self::IO(io) => write!(f, "io error: {}", io),
+	    Self::Authentication(auth) => write!(f, "{}", auth),
 	    _ => f.write_str("unknown"),
 	}
     }
@@ -454,6 +488,7 @@ impl error::Error for ProcessError
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
 	Some(match self.kind {
 	    ProcessErrorKind::IO(ref io) => io,
+	    ProcessErrorKind::Authentication(ref auth) => auth,
 	    _ => return None
 	})
     }
@@ -475,7 +510,11 @@ impl From<io::Error> for ProcessError
     fn from(from: io::Error) -> Self
     {
 	Self {
-	    kind: ProcessErrorKind::IO(from),
+	    kind: if cha::AuthenticationError::is_cause_of(&from) {
+		ProcessErrorKind::Authentication(cha::AuthenticationError)
+	    } else {
+		ProcessErrorKind::IO(from)
+	    },
 	    context: None,
 	}
     }
@@ -483,6 +522,31 @@ impl From<io::Error> for ProcessError
 
 impl ProcessError
 {
+    #[inline] 
+    pub fn kind(&self) -> &ProcessErrorKind
+    {
+	&self.kind
+    }
+    /// Did the input fail authentication?
+    ///
+    /// Processing cannot fall back to streaming after this, as the input has already been consumed.
+    #[inline] 
+    pub fn is_authentication(&self) -> bool
+    {
+	matches!(self.kind, ProcessErrorKind::Authentication(_))
+    }
+    /// Was the input rejected, either failing authentication or being invalid (e.g. a malformed header)?
+    ///
+    /// Processing should not fall back to streaming after this, as the input may have already been consumed, and would be rejected again anyway.
+    #[inline] 
+    pub fn is_rejection(&self) -> bool
+    {
+	match &self.kind {
+	    ProcessErrorKind::Authentication(_) => true,
+	    ProcessErrorKind::IO(io) => io.kind() == io::ErrorKind::InvalidData,
+	    _ => false,
+	}
+    }
     #[inline] 
     pub fn context_mut(&mut self) -> Option<&mut Dynamic>
     {
@@ -532,7 +596,9 @@ pub fn reopen_rw(fd: &(impl AsRawFd+?Sized)) -> io::Result<()>
 }
 
 /// Create an optimised call table for the cryptographic transformation from `from` to `to`.
-pub fn try_create_process<T: AsRawFd + io::Read, U: AsRawFd + io::Write>(from: T, to: U) -> Result<OpTable<T, U>, ProcessError>
+///
+/// The output is sized for the output of `op`, i.e. with room for the authentication tag(s) when encrypting.
+pub fn try_create_process<T: AsRawFd + io::Read, U: AsRawFd + io::Write>(from: T, to: U, op: &cha::Operation) -> Result<OpTable<T, U>, ProcessError>
 {
     let (input, buffsz) = match sized_then_or(from, |input, input_size| {
 	(match MappedFile::try_new(input, input_size, Perm::Readonly, Flags::Private) {
@@ -540,17 +606,21 @@ pub fn try_create_process<T: AsRawFd + io::Read, U: AsRawFd + io::Write>(from: T
 	    Err(e) => Err(e.into_inner()),
 	}, input_size)
     }) {
-	Ok((i, bs)) => (i, Some(bs)),
+	Ok((i, bs)) => (i, Some(bs).filter(|&bs| bs > 0)),
 	Err(e) => (Err(e), None),
     };
     
     let (output, outsz) = {
-	if let Some(buffsz) = buffsz.or_else(|| raw_file_size(&to).ok().and_then(|x| usize::try_from(x).ok())) {
+	let wanted = match &input {
+	    Ok(input) => op.output_size_of(&input[..]),
+	    Err(_) => buffsz.and_then(|sz| op.output_size(sz)),
+	};
+	if let Some(buffsz) = wanted.filter(|&sz| sz > 0) {
 	    match map_size_or(to, buffsz, |mmap, size| {
 		(mmap, size)
 	    }) {
 		Ok((m, s)) => (Ok(m), Some(s)),
-		Err(e) => (Err(e), if buffsz == 0 { None } else { Some(buffsz) }),
+		Err(e) => (Err(e), Some(buffsz)),
 	    }
 	} else {
 	    (Err(to), None)
@@ -559,24 +629,78 @@ pub fn try_create_process<T: AsRawFd + io::Read, U: AsRawFd + io::Write>(from: T
 
     Ok(match ((input, buffsz), (output, outsz)) {
 	// Check for all combinations of mapping successes or failures
-	((Ok(min), isz), (Ok(mout), osz)) => OpTable::Both(min, mout),
-	((Ok(min), isz), (Err(sout), osz)) => OpTable::Input(min, create_sized_temp_mapping(isz.or(osz).unwrap_or(0))?.0, sout),
-	((Err(sin), isz), (Ok(mout), osz)) => OpTable::Output(sin, create_sized_basic_mapping(osz.or(isz).unwrap_or(0))?, mout),
-	((Err(sin), isz), (Err(sout), osz)) => OpTable::Neither(sin, sout),
+	((Ok(min), _), (Ok(mout), _)) => OpTable::Both(min, mout),
+	((Ok(min), _), (Err(sout), Some(osz))) => OpTable::Input(min, create_sized_temp_mapping(osz)?.0, sout),
+	((Err(sin), Some(isz)), (Ok(mout), _)) => OpTable::Output(sin, create_sized_basic_mapping(isz)?, mout),
+	((Ok(min), _), (Err(sout), None)) => OpTable::Neither(min.into_inner(), sout),
+	((Err(sin), None), (Ok(mout), _)) => OpTable::Neither(sin, mout.into_inner()),
+	((Err(sin), _), (Err(sout), _)) => OpTable::Neither(sin, sout),
     })
 }
 
 #[inline]
 //TODO: Add metrics, status, progress, diagnostics, etc. reporting
-pub fn try_process(mode: impl BorrowMut<Crypter>) -> Result<usize, ProcessError>
+pub fn try_process(op: cha::Operation) -> Result<(usize, Option<cha::Tag>), ProcessError>
 {
     let sin = io::stdin().lock();
     let sout = io::stdout().lock();
-    let proc = try_create_process(sin, sout)?;
+    let proc = try_create_process(sin, sout, &op)?;
     if cfg!(debug_assertions) {
 	eprintln!("Process is: {:?}", proc);
     }
-    Ok(proc.execute(mode)?)
+    proc.execute(&op).map_err(ProcessError::from)
+}
+
+/// Check the authenticity of the ciphertext on stdin by mapping it, without writing any plaintext.
+///
+/// Fails with an I/O error if stdin cannot be mapped, in which case none of it has been read.
+pub fn try_verify(op: &cha::Operation) -> Result<usize, ProcessError>
+{
+    let mut input = map_stdin()?;
+    let _ = input.advise(mapped_file::Advice::Sequential, Some(true));
+    op.verify(&input[..])?;
+    Ok(input.len())
+}
+
+/// Decrypt only a range of the plaintext of the ciphertext on stdin to stdout by mapping it, see `cha::Operation::process_range()`.
+///
+/// The input is advised for random access, so the pages of it that are not needed for the range are never read.
+/// Fails with an I/O error if stdin cannot be mapped, in which case none of it has been read.
+pub fn try_process_range(op: &cha::Operation, offset: usize, length: Option<usize>) -> Result<usize, ProcessError>
+{
+    let mut input = map_stdin()?;
+    let _ = input.advise(mapped_file::Advice::RandomAccess, None);
+    let mut sout = io::stdout().lock();
+    let len = op.process_range(&input[..], offset, length, &mut sout)?;
+    io::Write::flush(&mut sout)?;
+    Ok(len)
+}
+
+/// Rewrite the header of the ciphertext in `file` in place with `rewrite`, by mapping only the header, see `header::rewrite()`.
+///
+/// The ciphertext following the header is never read or written. Returns the size of the header.
+pub fn rewrite_header(file: &fs::File, rewrite: impl FnOnce(&header::Header) -> Result<header::Header, header::Error>) -> Result<usize, ProcessError>
+{
+    let size = std::cmp::min(raw_file_size(file)?, header::MAX_SIZE as u64) as usize;
+    if size == 0 {
+	return Err(io::Error::from(header::Error::Truncated).into());
+    }
+    let mut map = MappedFile::new(file.as_fd(), size, Perm::ReadWrite, Flags::Shared)?;
+    let _ = map.advise(mapped_file::Advice::RandomAccess, None);
+    let header = header::rewrite(&map[..], rewrite).map_err(io::Error::from)?;
+    map[..header.len()].copy_from_slice(&header[..]);
+    map.flush(mapped_file::Flush::Wait)?;
+    Ok(header.len())
+}
+
+/// Map all of stdin read-only
+fn map_stdin() -> Result<MappedFile<io::StdinLock<'static>>, ProcessError>
+{
+    let sin = io::stdin().lock();
+    match sized_then_or(sin, |input, size| MappedFile::try_new(input, size, Perm::Readonly, Flags::Private)) {
+	Ok(Ok(input)) => Ok(input),
+	_ => Err(io::Error::new(io::ErrorKind::Unsupported, "stdin cannot be mapped").into()),
+    }
 }
 
     #[cfg(feature="try_process-old")] 
@@ -677,3 +801,41 @@ pub fn try_process(mut mode: impl BorrowMut<Crypter>) -> io::Result<io::Result<(
     //todo!("return Ok(process_mapped_files(mode, mstdin, mstdout, key, iv))")
 }
     };
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::io::{Read, Seek, Write};
+
+    /// Ciphertext that fails authentication, streamed from a pipe into a file, leaves no plaintext in the file
+    #[test]
+    fn pipe_to_file_tampered()
+    {
+	let (key, iv) = cha::keygen();
+	let input = vec![0x5au8; 100000];
+	let enc = cha::Operation::new(cha::Direction::Encrypt, key.clone(), iv);
+	let mut ciphertext = vec![0u8; enc.output_size(input.len()).unwrap()];
+	enc.process(&input[..], &mut ciphertext[..]).expect("encrypt");
+	ciphertext[input.len() - 1] ^= 1;
+
+	let path = std::env::temp_dir().join(format!("chacha20-mapped-test-{}", std::process::id()));
+	let mut output = fs::OpenOptions::new().read(true).write(true).create_new(true).open(&path).expect("output file");
+	fs::remove_file(&path).unwrap();
+	output.write_all(b"kept").unwrap();
+
+	let (sin, mut writer) = io::pipe().expect("pipe");
+	let feed = std::thread::spawn(move || writer.write_all(&ciphertext[..]));
+	let op = cha::Operation::new(cha::Direction::Decrypt, key, iv);
+	let proc = try_create_process(sin, output.try_clone().unwrap(), &op).expect("create process");
+	assert!(matches!(proc, OpTable::Neither(..)), "{:?}", proc);
+	let err = proc.execute(&op).expect_err("tampered ciphertext was accepted");
+	assert!(cha::AuthenticationError::is_cause_of(&err));
+	feed.join().unwrap().unwrap();
+
+	let mut written = Vec::new();
+	output.rewind().unwrap();
+	output.read_to_end(&mut written).unwrap();
+	assert_eq!(&written[..], b"kept");
+    }
+}
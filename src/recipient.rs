@@ -0,0 +1,516 @@
+//! Encrypting to several recipients, by wrapping a file key
+//!
+//! A ciphertext with recipients is encrypted with a new random file key, which is wrapped (encrypted with ChaCha20-Poly1305) once for each recipient and stored in the header as a `Stanza`.
+//! Recipient sections are not authenticated along with the ciphertext, so recipients can be added or removed without re-encrypting it; a stanza that is tampered with just fails to unwrap.
+//!
+//! The key that wraps the file key is derived with `kdf::hkdf()`, and is unique to its stanza (so the IV is always zero.) It is derived from:
+//! * A symmetric key, with a random salt stored in the stanza.
+//! * The key derived from a passphrase with the `Kdf` stored in the stanza.
+//! * The X25519 shared secret of a new ephemeral key pair and the recipient's public key, with the ephemeral public key stored in the stanza.
+//!   The recipient computes the same secret from their private key (their `Identity`) and the ephemeral public key.
+//!   The stanza also has a short hint of the recipient's public key, keyed with the file key, so that anyone who can decrypt the ciphertext can tell which stanza is whose (see `Rewrap`.)
+use openssl::{
+    pkey::{PKey, Id, Private, Public},
+    derive::Deriver,
+    error::ErrorStack,
+};
+use std::{fmt, str};
+use crate::key::{Key, IV, KeyParseError, Encoding, decode_text};
+use crate::cha::{self, KeySource, TAG_SIZE};
+use crate::kdf::{Kdf, SALT_SIZE};
+use crate::header::{
+    Header,
+    Section,
+    Error as HeaderError,
+};
+use crate::secure::Locked;
+
+/// The size of X25519 public and private keys
+pub const KEY_SIZE: usize = 32;
+/// The size of a wrapped file key, with its tag
+pub const WRAPPED_SIZE: usize = cha::KEY_SIZE + TAG_SIZE;
+
+/// The size of the hint of the recipient of an X25519 stanza
+pub const HINT_SIZE: usize = 8;
+
+/// The `info` of the HKDF that derives a wrapping key, followed by the kind of the stanza
+const INFO: &[u8] = b"chacha20 file key";
+/// The `info` of the HKDF that derives the hint of a recipient's public key from the file key
+const HINT_INFO: &[u8] = b"chacha20 recipient hint";
+
+/// The IDs of stanza `Kind`s
+mod kind {
+    pub const X25519: u8 = 1;
+    pub const KEY: u8 = 2;
+    pub const PASSPHRASE: u8 = 3;
+}
+
+/// The X25519 public key of a recipient
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PublicKey([u8; KEY_SIZE]);
+
+/// The X25519 private key of a recipient, which decrypts ciphertexts encrypted to its `PublicKey`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity(Key);
+
+impl PublicKey
+{
+    fn pkey(&self) -> Result<PKey<Public>, ErrorStack>
+    {
+	PKey::public_key_from_raw_bytes(&self.0[..], Id::X25519)
+    }
+}
+
+impl AsRef<[u8]> for PublicKey
+{
+    #[inline] fn as_ref(&self) -> &[u8]
+    {
+	&self.0[..]
+    }
+}
+
+impl Identity
+{
+    /// Generate a new random identity
+    pub fn new() -> Self
+    {
+	let pkey = PKey::generate_x25519().expect("x25519 keygen fatal");
+	let raw = Locked::new(pkey.raw_private_key().expect("x25519 keygen fatal"));
+	let mut key = Key::default();
+	key.expose_secret_mut().copy_from_slice(&raw[..]);
+	Self(key)
+    }
+
+    /// Decode an identity stored in the same formats as a key file, see `Key::decode()`
+    #[inline] pub fn decode(data: &[u8]) -> Result<Self, KeyParseError>
+    {
+	Key::decode(data).map(Self)
+    }
+
+    /// The private key, as it is stored in an identity file
+    #[inline] pub fn expose_secret(&self) -> &Key
+    {
+	&self.0
+    }
+
+    fn pkey(&self) -> Result<PKey<Private>, ErrorStack>
+    {
+	PKey::private_key_from_raw_bytes(&self.0.expose_secret()[..], Id::X25519)
+    }
+
+    /// The public key that ciphertexts are encrypted to for this identity
+    pub fn public_key(&self) -> Result<PublicKey, ErrorStack>
+    {
+	let mut public = [0u8; KEY_SIZE];
+	public.copy_from_slice(&self.pkey()?.raw_public_key()?[..]);
+	Ok(PublicKey(public))
+    }
+}
+
+/// How the file key in a `Stanza` is wrapped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Kind
+{
+    /// With a symmetric key, and this random salt
+    Key {
+	salt: [u8; SALT_SIZE],
+    },
+    /// With the key derived from a passphrase with this `Kdf` (which includes its salt)
+    Passphrase {
+	kdf: Kdf,
+    },
+    /// For an X25519 public key, with the shared secret of this ephemeral public key.
+    ///
+    /// `hint` is derived from the file key and the recipient's public key, see `hint()`.
+    X25519 {
+	ephemeral: PublicKey,
+	hint: [u8; HINT_SIZE],
+    },
+}
+
+impl Kind
+{
+    /// The ID of this kind in a `Stanza`
+    #[inline] fn id(&self) -> u8
+    {
+	match self {
+	    Self::Key { .. } => kind::KEY,
+	    Self::Passphrase { .. } => kind::PASSPHRASE,
+	    Self::X25519 { .. } => kind::X25519,
+	}
+    }
+
+    /// Encode this kind and its parameters, which are authenticated along with the wrapped file key
+    fn to_bytes(self) -> Vec<u8>
+    {
+	let mut output = vec![self.id()];
+	match self {
+	    Self::Key { salt } => output.extend_from_slice(&salt[..]),
+	    Self::Passphrase { kdf } => output.extend_from_slice(&kdf.to_bytes()[..]),
+	    Self::X25519 { ephemeral, hint } => {
+		output.extend_from_slice(ephemeral.as_ref());
+		output.extend_from_slice(&hint[..]);
+	    },
+	}
+	output
+    }
+}
+
+/// A file key wrapped for one recipient, stored in a `header::Section::Recipient`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Stanza
+{
+    pub kind: Kind,
+    /// The encrypted file key, followed by its tag
+    pub wrapped: [u8; WRAPPED_SIZE],
+}
+
+impl Stanza
+{
+    /// Encode this stanza as the data of a `header::Section::Recipient`
+    pub fn to_bytes(self) -> Vec<u8>
+    {
+	let mut output = self.kind.to_bytes();
+	output.extend_from_slice(&self.wrapped[..]);
+	output
+    }
+
+    /// Decode the data of a `header::Section::Recipient`
+    pub fn from_bytes(data: &[u8]) -> Result<Self, HeaderError>
+    {
+	let malformed = || HeaderError::Malformed("recipient");
+	let (&id, data) = data.split_first().ok_or_else(malformed)?;
+	let split = data.len().checked_sub(WRAPPED_SIZE).ok_or_else(malformed)?;
+	let (params, wrapped) = data.split_at(split);
+	let kind = match id {
+	    kind::KEY => Kind::Key {
+		salt: params.try_into().map_err(|_| malformed())?,
+	    },
+	    kind::PASSPHRASE => Kind::Passphrase {
+		kdf: Kdf::from_bytes(params)?,
+	    },
+	    kind::X25519 if params.len() == KEY_SIZE + HINT_SIZE => Kind::X25519 {
+		ephemeral: PublicKey(params[..KEY_SIZE].try_into().unwrap()),
+		hint: params[KEY_SIZE..].try_into().unwrap(),
+	    },
+	    kind::X25519 => return Err(malformed()),
+	    other => return Err(HeaderError::UnknownRecipient(other)),
+	};
+	Ok(Self {
+	    kind,
+	    wrapped: wrapped.try_into().unwrap(),
+	})
+    }
+
+    /// The size of the encoding of a stanza wrapped for `recipient`, without wrapping anything.
+    ///
+    /// # Panics
+    /// If `recipient` is a `KeySource::Recipients`.
+    pub fn size_for(recipient: &KeySource) -> usize
+    {
+	1 + WRAPPED_SIZE + match recipient {
+	    KeySource::Key(_) => SALT_SIZE,
+	    KeySource::Passphrase(_, kdf) => kdf.unwrap_or_default().to_bytes().len(),
+	    KeySource::Recipient(_) | KeySource::Identity(_) => KEY_SIZE + HINT_SIZE,
+	    KeySource::Recipients(_) => panic!("recipients cannot be nested"),
+	}
+    }
+}
+
+/// The X25519 shared secret of `private` and `public`
+fn agree(private: &PKey<Private>, public: &PublicKey) -> Result<Key, HeaderError>
+{
+    let agree = || -> Result<Key, ErrorStack> {
+	let public = public.pkey()?;
+	let mut deriver = Deriver::new(private)?;
+	deriver.set_peer(&public)?;
+	let mut shared = Key::default();
+	deriver.derive(&mut shared.expose_secret_mut()[..])?;
+	Ok(shared)
+    };
+    match agree() {
+	// A low order public key gives an all-zero secret, which anyone could compute
+	Ok(shared) if shared != Key::default() => Ok(shared),
+	_ => Err(HeaderError::KeyAgreement),
+    }
+}
+
+/// The key that wraps the file key in a stanza of `kind`, derived from `secret`
+fn wrapping_key(kind: &Kind, secret: &Key, salt: &[u8]) -> Key
+{
+    let mut info = INFO.to_vec();
+    info.push(kind.id());
+    let mut key = Key::default();
+    crate::kdf::hkdf(&secret.expose_secret()[..], salt, &info[..], &mut key.expose_secret_mut()[..]).expect("hkdf fatal");
+    key
+}
+
+/// The hint of `recipient` in an X25519 stanza wrapping `file_key`
+fn hint(file_key: &Key, recipient: &PublicKey) -> [u8; HINT_SIZE]
+{
+    let mut hint = [0u8; HINT_SIZE];
+    crate::kdf::hkdf(&file_key.expose_secret()[..], recipient.as_ref(), HINT_INFO, &mut hint[..]).expect("hkdf fatal");
+    hint
+}
+
+/// Wrap `file_key` in a stanza of `kind`
+fn seal_key(kind: Kind, wrapping: &Key, file_key: &Key) -> Stanza
+{
+    let mut wrapped = [0u8; WRAPPED_SIZE];
+    let mut crypter = cha::encrypter(wrapping, IV::default(), kind.to_bytes()).expect("file key wrapping fatal");
+    crypter.update(&file_key.expose_secret()[..], &mut wrapped[..cha::KEY_SIZE]).expect("file key wrapping fatal");
+    wrapped[cha::KEY_SIZE..].copy_from_slice(&cha::seal(&mut crypter).expect("file key wrapping fatal")[..]);
+    Stanza { kind, wrapped }
+}
+
+/// Unwrap the file key in `stanza`, if it was wrapped with `wrapping`
+fn open_key(stanza: &Stanza, wrapping: &Key) -> Option<Key>
+{
+    let mut file_key = Key::default();
+    let mut crypter = cha::decrypter(wrapping, IV::default(), stanza.kind.to_bytes()).ok()?;
+    crypter.update(&stanza.wrapped[..cha::KEY_SIZE], &mut file_key.expose_secret_mut()[..]).ok()?;
+    cha::open(&mut crypter, &stanza.wrapped[cha::KEY_SIZE..]).ok()?;
+    Some(file_key)
+}
+
+/// Wrap `file_key` for `recipient`.
+///
+/// An `Identity` is treated as its public key.
+///
+/// # Panics
+/// If `recipient` is a `KeySource::Recipients`.
+pub fn wrap(recipient: &KeySource, file_key: &Key) -> Result<Stanza, HeaderError>
+{
+    let stanza = match recipient {
+	KeySource::Key(key) => {
+	    let mut salt = [0u8; SALT_SIZE];
+	    getrandom::getrandom(&mut salt[..]).expect("rng fatal");
+	    let kind = Kind::Key { salt };
+	    seal_key(kind, &wrapping_key(&kind, key, &salt[..]), file_key)
+	},
+	KeySource::Passphrase(passphrase, kdf) => {
+	    let kdf = kdf.unwrap_or_default();
+	    let kind = Kind::Passphrase { kdf };
+	    let secret = kdf.derive(&passphrase[..])?;
+	    seal_key(kind, &wrapping_key(&kind, &secret, &[]), file_key)
+	},
+	KeySource::Recipient(public) => {
+	    let private = Identity::new();
+	    let ephemeral = private.public_key().map_err(|_| HeaderError::KeyAgreement)?;
+	    let shared = agree(&private.pkey().map_err(|_| HeaderError::KeyAgreement)?, public)?;
+	    let kind = Kind::X25519 { ephemeral, hint: hint(file_key, public) };
+	    seal_key(kind, &wrapping_key(&kind, &shared, &[ephemeral.as_ref(), public.as_ref()].concat()), file_key)
+	},
+	KeySource::Identity(identity) => return wrap(&KeySource::Recipient(identity.public_key().map_err(|_| HeaderError::KeyAgreement)?), file_key),
+	KeySource::Recipients(_) => panic!("recipients cannot be nested"),
+    };
+    Ok(stanza)
+}
+
+/// Unwrap the file key in `stanza` with `identity`.
+///
+/// Returns `None` if `identity` cannot unwrap stanzas of its kind, or the stanza was wrapped for another recipient (or has an invalid ephemeral key.)
+pub fn unwrap(identity: &KeySource, stanza: &Stanza) -> Result<Option<Key>, HeaderError>
+{
+    let wrapping = match (identity, &stanza.kind) {
+	(KeySource::Key(key), Kind::Key { salt }) => wrapping_key(&stanza.kind, key, &salt[..]),
+	(KeySource::Passphrase(passphrase, _), Kind::Passphrase { kdf }) => wrapping_key(&stanza.kind, &kdf.derive(&passphrase[..])?, &[]),
+	(KeySource::Identity(identity), Kind::X25519 { ephemeral, .. }) => {
+	    // Stanzas are not authenticated, so anyone can add one with a bad ephemeral key
+	    let Ok(shared) = agree(&identity.pkey().map_err(|_| HeaderError::KeyAgreement)?, ephemeral) else {
+		return Ok(None);
+	    };
+	    let public = identity.public_key().map_err(|_| HeaderError::KeyAgreement)?;
+	    wrapping_key(&stanza.kind, &shared, &[ephemeral.as_ref(), public.as_ref()].concat())
+	},
+	_ => return Ok(None),
+    };
+    Ok(open_key(stanza, &wrapping))
+}
+
+/// Check that no more than one of `stanzas` is wrapped with a passphrase.
+///
+/// Stanzas are not authenticated, and trying a passphrase stanza runs its KDF (with the parameters in the stanza), so a header with many of them could make opening it take hours.
+pub fn check_passphrases<'a>(stanzas: impl IntoIterator<Item = &'a Kind>) -> Result<(), HeaderError>
+{
+    if stanzas.into_iter().filter(|kind| matches!(kind, Kind::Passphrase { .. })).count() > 1 {
+	return Err(HeaderError::MultiplePassphrases);
+    }
+    Ok(())
+}
+
+/// Create a new file key, and wrap it for each of `recipients`.
+///
+/// Fails with `MultiplePassphrases` if more than one of them is a passphrase.
+pub fn seal(recipients: &[KeySource]) -> Result<(Key, Vec<Stanza>), HeaderError>
+{
+    if recipients.iter().filter(|recipient| matches!(recipient, KeySource::Passphrase(..))).count() > 1 {
+	return Err(HeaderError::MultiplePassphrases);
+    }
+    let file_key = Key::new();
+    let stanzas = recipients.iter()
+	.map(|recipient| wrap(recipient, &file_key))
+	.collect::<Result<_, _>>()?;
+    Ok((file_key, stanzas))
+}
+
+/// Unwrap the file key from the first of `stanzas` that one of `identities` (tried in order) can unwrap.
+///
+/// A stanza that fails to unwrap does not stop the others from being tried, as stanzas are not authenticated.
+/// If none of them unwrap, the first error (e.g. a passphrase stanza's KDF failing) is returned, or else `NotRecipient`.
+/// Fails with `MultiplePassphrases` before trying any of them if more than one is a passphrase stanza (see `check_passphrases()`.)
+pub fn open(identities: &[KeySource], stanzas: &[Stanza]) -> Result<Key, HeaderError>
+{
+    check_passphrases(stanzas.iter().map(|stanza| &stanza.kind))?;
+    let mut error = None;
+    for identity in identities {
+	for stanza in stanzas {
+	    match unwrap(identity, stanza) {
+		Ok(Some(file_key)) => return Ok(file_key),
+		Ok(None) => (),
+		Err(err) => error = error.or(Some(err)),
+	    }
+	}
+    }
+    Err(error.unwrap_or(HeaderError::NotRecipient))
+}
+
+/// Changes to the recipients of a ciphertext with a file key, which are made without re-encrypting it.
+///
+/// Removing a recipient only removes their wrapped copy of the file key from the header. If they kept the file key (or a copy of the ciphertext as it was,) they can still decrypt it.
+#[derive(Debug, Clone, Default)]
+pub struct Rewrap
+{
+    /// Wrap the file key for each of these
+    pub add: Vec<KeySource>,
+    /// Remove the stanzas of each of these: the X25519 stanzas of a public key (or identity,) or the stanzas a key or passphrase unwraps
+    pub remove: Vec<KeySource>,
+    /// Remove every passphrase stanza, before adding any
+    pub remove_passphrases: bool,
+}
+
+impl Rewrap
+{
+    /// Is `stanza` (which wraps `file_key`) the stanza of one of the recipients to remove?
+    fn removes(&self, stanza: &Stanza, file_key: &Key) -> Result<bool, HeaderError>
+    {
+	if self.remove_passphrases && matches!(stanza.kind, Kind::Passphrase { .. }) {
+	    return Ok(true);
+	}
+	for recipient in &self.remove {
+	    let public = match recipient {
+		KeySource::Recipient(public) => Some(*public),
+		KeySource::Identity(identity) => Some(identity.public_key().map_err(|_| HeaderError::KeyAgreement)?),
+		_ => None,
+	    };
+	    let removed = match (public, &stanza.kind) {
+		(Some(public), &Kind::X25519 { hint: stanza_hint, .. }) => stanza_hint == hint(file_key, &public),
+		(Some(_), _) => false,
+		(None, _) => unwrap(recipient, stanza)?.is_some(),
+	    };
+	    if removed {
+		return Ok(true);
+	    }
+	}
+	Ok(false)
+    }
+
+    /// Apply these changes to the recipients of `header`, unwrapping its file key with one of `identities` (see `open()`.)
+    ///
+    /// Only the unbound recipient sections change, so the ciphertext following the header is still authenticated with the new header.
+    /// A public key that the file key is already wrapped for is not added again.
+    pub fn apply(&self, header: &Header, identities: &[KeySource]) -> Result<Header, HeaderError>
+    {
+	let stanzas = header.recipients()?;
+	if stanzas.is_empty() {
+	    return Err(HeaderError::NoFileKey);
+	}
+	let file_key = open(identities, &stanzas)?;
+	let mut kept = Vec::with_capacity(stanzas.len() + self.add.len());
+	for stanza in stanzas {
+	    if !self.removes(&stanza, &file_key)? {
+		kept.push(stanza);
+	    }
+	}
+	let added = self.add.iter().filter(|recipient| matches!(recipient, KeySource::Passphrase(..))).count();
+	if added > 1 || (added == 1 && kept.iter().any(|stanza| matches!(stanza.kind, Kind::Passphrase { .. }))) {
+	    return Err(HeaderError::MultiplePassphrases);
+	}
+	for recipient in &self.add {
+	    if let KeySource::Recipient(public) = recipient {
+		let public_hint = hint(&file_key, public);
+		if kept.iter().any(|stanza| matches!(stanza.kind, Kind::X25519 { hint, .. } if hint == public_hint)) {
+		    continue;
+		}
+	    }
+	    kept.push(wrap(recipient, &file_key)?);
+	}
+	if kept.is_empty() {
+	    return Err(HeaderError::NoRecipients);
+	}
+
+	let mut header = header.clone();
+	header.sections.retain(|section| !matches!(section, Section::Recipient(_)));
+	header.sections.extend(kept.into_iter().map(|stanza| Section::Recipient(stanza.to_bytes())));
+	Ok(header)
+    }
+}
+
+impl fmt::Display for PublicKey
+{
+    /// Base64, as keys are printed
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+	f.write_str(&Encoding::Base64.encode(self))
+    }
+}
+
+impl str::FromStr for PublicKey
+{
+    type Err = KeyParseError;
+
+    /// Parse a public key of exactly `KEY_SIZE` bytes, in any `Encoding`
+    #[inline] fn from_str(s: &str) -> Result<Self, Self::Err> {
+	decode_text(s.trim(), false).map(Self)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    /// A stanza that cannot be unwrapped does not lock the other recipients out
+    #[test]
+    fn junk_stanzas()
+    {
+	let identity = Identity::new();
+	let file_key = Key::new();
+	let stanza = wrap(&KeySource::Recipient(identity.public_key().unwrap()), &file_key).unwrap();
+	// A low order ephemeral key, whose shared secret with any identity is all zeroes
+	let low_order = Stanza { kind: Kind::X25519 { ephemeral: PublicKey([0u8; KEY_SIZE]), hint: [0u8; HINT_SIZE] }, ..stanza };
+	let bad_kdf = Stanza { kind: Kind::Passphrase { kdf: Kdf::scrypt(u8::MAX, 8, 1) }, ..stanza };
+
+	let identities = [KeySource::Passphrase(b"hunter2".to_vec().into(), None), KeySource::Identity(identity.clone())];
+	assert_eq!(open(&identities, &[low_order, bad_kdf, stanza]).as_ref(), Ok(&file_key));
+	assert_eq!(open(&identities[1..], &[low_order]), Err(HeaderError::NotRecipient));
+	assert!(open(&identities, &[low_order, bad_kdf]).is_err());
+    }
+
+    #[test]
+    fn multiple_passphrases()
+    {
+	let passphrase = KeySource::Passphrase(b"hunter2".to_vec().into(), None);
+	let file_key = Key::new();
+	let stanza = wrap(&KeySource::Recipient(Identity::new().public_key().unwrap()), &file_key).unwrap();
+	// Each of these would take a few seconds to try, if the KDF ran
+	let expensive = Stanza { kind: Kind::Passphrase { kdf: Kdf::scrypt(19, 8, 1) }, ..stanza };
+	let stanzas = vec![expensive; 64];
+	assert_eq!(open(std::slice::from_ref(&passphrase), &stanzas), Err(HeaderError::MultiplePassphrases));
+
+	let mut header = Header::new(cha::CipherSuite::default(), cha::CipherSuite::default().new_nonce(), cha::Framing::default()).unwrap();
+	header.sections.extend(stanzas.into_iter().map(|stanza| Section::Recipient(stanza.to_bytes())));
+	assert_eq!(header.recipients().err(), Some(HeaderError::MultiplePassphrases));
+	assert_eq!(seal(&[passphrase.clone(), passphrase]).err(), Some(HeaderError::MultiplePassphrases));
+    }
+}
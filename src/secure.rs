@@ -0,0 +1,297 @@
+//! Keeping secrets and plaintext out of swap and core dumps
+use std::{
+    io,
+    ops,
+    collections::BTreeMap,
+    sync::{Mutex, Once},
+};
+use zeroize::Zeroize;
+
+/// How many `Locked` regions each locked page is part of.
+///
+/// `mlock()` does not nest, so a page is only unlocked once none of the regions on it are alive.
+static PAGES: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+
+/// Warn about the first failure to lock memory
+static WARN: Once = Once::new();
+
+#[inline] fn page_size() -> usize
+{
+    match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+	size if size > 0 => size as usize,
+	_ => 4096,
+    }
+}
+
+/// The pages `region` is on
+fn pages(region: &[u8]) -> ops::Range<usize>
+{
+    let page = page_size();
+    let start = region.as_ptr() as usize;
+    (start / page)..((start + region.len()).div_ceil(page))
+}
+
+/// Call `f` with the start and length of each run of consecutive pages in `pages`
+fn each_run(pages: impl IntoIterator<Item = usize>, mut f: impl FnMut(*mut libc::c_void, usize) -> io::Result<()>) -> io::Result<()>
+{
+    let page = page_size();
+    let mut run: Option<ops::Range<usize>> = None;
+    let mut result = Ok(());
+    let mut flush = |run: ops::Range<usize>| if let Err(err) = f((run.start * page) as *mut _, run.len() * page) {
+	result = Err(err);
+    };
+    for p in pages {
+	run = match run {
+	    Some(run) if run.end == p => Some(run.start..(p + 1)),
+	    Some(run) => {
+		flush(run);
+		Some(p..(p + 1))
+	    },
+	    None => Some(p..(p + 1)),
+	};
+    }
+    if let Some(run) = run {
+	flush(run);
+    }
+    result
+}
+
+#[inline] fn check(ret: libc::c_int) -> io::Result<()>
+{
+    match ret {
+	0 => Ok(()),
+	_ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Lock the pages of `region` into memory and exclude them from core dumps.
+///
+/// This is best-effort: if it fails (e.g. because of `RLIMIT_MEMLOCK`,) a warning is printed once and the memory is used anyway.
+fn lock(region: &[u8])
+{
+    if region.is_empty() {
+	return;
+    }
+    let mut locked = PAGES.lock().unwrap_or_else(|e| e.into_inner());
+    let new: Vec<usize> = pages(region).filter(|&p| {
+	let count = locked.entry(p).or_insert(0);
+	*count += 1;
+	*count == 1
+    }).collect();
+    let result = each_run(new, |start, len| unsafe {
+	check(libc::mlock(start, len))?;
+	check(libc::madvise(start, len, libc::MADV_DONTDUMP))
+    });
+    if let Err(err) = result {
+	WARN.call_once(|| eprintln!("{}: warning: failed to lock memory, secrets may be written to swap or core dumps: {}", env!("CARGO_PKG_NAME"), err));
+    }
+}
+
+/// Undo `lock()` for `region`, unlocking the pages that are no longer part of any locked region.
+fn unlock(region: &[u8])
+{
+    if region.is_empty() {
+	return;
+    }
+    let mut locked = PAGES.lock().unwrap_or_else(|e| e.into_inner());
+    let free: Vec<usize> = pages(region).filter(|p| match locked.get_mut(p) {
+	Some(1) => {
+	    locked.remove(p);
+	    true
+	},
+	Some(count) => {
+	    *count -= 1;
+	    false
+	},
+	None => false,
+    }).collect();
+    let _ = each_run(free, |start, len| unsafe {
+	check(libc::munlock(start, len))?;
+	check(libc::madvise(start, len, libc::MADV_DODUMP))
+    });
+}
+
+/// Stop the process from being dumped (or attached to by other unprivileged processes) with `PR_SET_DUMPABLE`.
+pub fn disable_dumps() -> io::Result<()>
+{
+    check(unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 0, 0, 0, 0) })
+}
+
+/// A buffer that is locked into memory and excluded from core dumps for as long as it is alive.
+///
+/// It is zeroed out when it is dropped. The buffer cannot be reallocated through a `Locked`, as the new memory would not be locked.
+pub struct Locked<B>(B)
+where B: ops::DerefMut,
+      B::Target: AsRef<[u8]> + AsMut<[u8]>;
+
+impl<B> Locked<B>
+where B: ops::DerefMut,
+      B::Target: AsRef<[u8]> + AsMut<[u8]>
+{
+    pub fn new(inner: B) -> Self
+    {
+	lock((*inner).as_ref());
+	Self(inner)
+    }
+}
+
+impl<B> ops::Drop for Locked<B>
+where B: ops::DerefMut,
+      B::Target: AsRef<[u8]> + AsMut<[u8]>
+{
+    fn drop(&mut self)
+    {
+	(*self.0).as_mut().zeroize();
+	unlock((*self.0).as_ref());
+    }
+}
+
+impl<B> ops::Deref for Locked<B>
+where B: ops::DerefMut,
+      B::Target: AsRef<[u8]> + AsMut<[u8]>
+{
+    type Target = B::Target;
+    #[inline] fn deref(&self) -> &Self::Target
+    {
+	&self.0
+    }
+}
+
+impl<B> ops::DerefMut for Locked<B>
+where B: ops::DerefMut,
+      B::Target: AsRef<[u8]> + AsMut<[u8]>
+{
+    #[inline] fn deref_mut(&mut self) -> &mut Self::Target
+    {
+	&mut self.0
+    }
+}
+
+impl<B> Clone for Locked<B>
+where B: ops::DerefMut + Clone,
+      B::Target: AsRef<[u8]> + AsMut<[u8]>
+{
+    #[inline] fn clone(&self) -> Self
+    {
+	Self::new(self.0.clone())
+    }
+}
+
+impl<B> Default for Locked<B>
+where B: ops::DerefMut + Default,
+      B::Target: AsRef<[u8]> + AsMut<[u8]>
+{
+    #[inline] fn default() -> Self
+    {
+	Self::new(B::default())
+    }
+}
+
+/// A growable `Locked` buffer.
+///
+/// When it grows, its contents are moved to a new locked allocation and the old one is zeroed out.
+#[derive(Default)]
+pub struct Buffer
+{
+    memory: Locked<Vec<u8>>,
+    len: usize,
+}
+
+impl Buffer
+{
+    #[inline] pub fn new() -> Self
+    {
+	Self::default()
+    }
+
+    /// An empty buffer that can grow to `capacity` bytes without reallocating
+    pub fn with_capacity(capacity: usize) -> Self
+    {
+	Self {
+	    memory: Locked::new(vec![0u8; capacity]),
+	    len: 0,
+	}
+    }
+
+    /// The size the buffer can grow to without reallocating
+    #[inline] pub fn capacity(&self) -> usize
+    {
+	self.memory.len()
+    }
+
+    /// Resize the buffer, filling any new bytes with `value`.
+    pub fn resize(&mut self, len: usize, value: u8)
+    {
+	if len > self.capacity() {
+	    let mut memory = Locked::new(vec![0u8; std::cmp::max(len, self.capacity() * 2)]);
+	    memory[..self.len].copy_from_slice(&self[..]);
+	    self.memory = memory;
+	}
+	if len > self.len {
+	    self.memory[self.len..len].fill(value);
+	}
+	self.len = len;
+    }
+
+    /// Append `data` to the buffer
+    pub fn extend_from_slice(&mut self, data: &[u8])
+    {
+	let start = self.len;
+	self.resize(start + data.len(), 0);
+	self.memory[start..self.len].copy_from_slice(data);
+    }
+
+    /// Empty the buffer, keeping it allocated.
+    ///
+    /// The contents are not zeroed out until the buffer is reallocated or dropped.
+    #[inline] pub fn clear(&mut self)
+    {
+	self.len = 0;
+    }
+}
+
+impl ops::Deref for Buffer
+{
+    type Target = [u8];
+    #[inline] fn deref(&self) -> &Self::Target
+    {
+	&self.memory[..self.len]
+    }
+}
+
+impl ops::DerefMut for Buffer
+{
+    #[inline] fn deref_mut(&mut self) -> &mut Self::Target
+    {
+	&mut self.memory[..self.len]
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn locked_buffer()
+    {
+	let mut buffer = Buffer::new();
+	buffer.extend_from_slice(b"Hello world!");
+	let capacity = buffer.capacity();
+	buffer.resize(10, 0);
+	buffer.extend_from_slice(b"?");
+	assert_eq!(&buffer[..], b"Hello worl?");
+	assert_eq!(buffer.capacity(), capacity);
+	// Growing moves the contents to a new allocation
+	buffer.resize(capacity + 1, 0xff);
+	assert_eq!(&buffer[..11], b"Hello worl?");
+	assert!(buffer[11..].iter().all(|&b| b == 0xff));
+	buffer.clear();
+	assert!(buffer.is_empty());
+
+	let mut buffer = Buffer::with_capacity(16);
+	assert!(buffer.is_empty());
+	buffer.extend_from_slice(b"Hello world!");
+	assert_eq!(buffer.capacity(), 16);
+    }
+}
@@ -0,0 +1,169 @@
+//! Deterministic nonces from a persisted counter, for encrypting more messages under one key than random nonces are safe for
+//!
+//! Each nonce is a random prefix chosen once per `NonceSequence`, followed by a `COUNTER_SIZE` byte big endian counter.
+//! The counter is stored in a state file, and is advanced (and synced) under a lock *before* its values are used, so a crash can skip values but never reuse them.
+//! When the counter runs out, no more nonces are generated, rather than wrapping around.
+use super::*;
+use key::Nonce;
+
+use std::{
+    fmt, error,
+    io::{self, Read},
+    fs,
+    path::PathBuf,
+};
+
+/// The size of the counter at the end of each nonce
+pub const COUNTER_SIZE: usize = 8;
+/// The size of the counter in the state file: zero padded decimal digits, followed by a newline
+const STATE_DIGITS: usize = 20;
+
+/// A generator of unique nonces for one key, see the module docs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NonceSequence
+{
+    state: PathBuf,
+    prefix: Vec<u8>,
+    /// The counter values that have been reserved, but not used yet
+    reserved: std::ops::Range<u64>,
+    reserve: u64,
+}
+
+impl NonceSequence
+{
+    /// A sequence of nonces of `nonce_size` bytes (see `cha::CipherSuite::nonce_size()`), with its counter stored in the file `state`.
+    ///
+    /// # Panics
+    /// If `nonce_size` is not larger than `COUNTER_SIZE`.
+    pub fn new(state: impl Into<PathBuf>, nonce_size: usize) -> Self
+    {
+	assert!(nonce_size > COUNTER_SIZE, "a nonce of {} bytes has no room for a random prefix", nonce_size);
+	let mut prefix = vec![0u8; nonce_size - COUNTER_SIZE];
+	getrandom::getrandom(&mut prefix[..]).expect("rng fatal");
+	Self {
+	    state: state.into(),
+	    prefix,
+	    reserved: 0..0,
+	    reserve: 1,
+	}
+    }
+
+    /// Reserve this many counter values from the state file at a time, so that it is not locked and synced for every nonce.
+    ///
+    /// Values that are reserved but not used when the sequence is dropped are skipped.
+    #[inline] pub fn with_reserve(self, reserve: u64) -> Self
+    {
+	Self { reserve: reserve.max(1), ..self }
+    }
+
+    /// The next nonce of the sequence.
+    ///
+    /// Fails if the state file cannot be read or updated, or the counter has run out.
+    pub fn next(&mut self) -> Result<Nonce, Error>
+    {
+	if self.reserved.is_empty() {
+	    self.reserved = self.advance()?;
+	}
+	let counter = self.reserved.next().unwrap();
+	Ok(Nonce::from_slice(&[&self.prefix[..], &counter.to_be_bytes()[..]].concat()).expect("nonce size"))
+    }
+
+    /// Advance the counter in the state file past `reserve` values, returning them
+    fn advance(&self) -> Result<std::ops::Range<u64>, Error>
+    {
+	use std::os::unix::{
+	    fs::{OpenOptionsExt, FileExt},
+	    io::AsRawFd,
+	};
+	let err = |e| Error::Io(self.state.clone(), e);
+	let mut file = fs::OpenOptions::new()
+	    .read(true)
+	    .write(true)
+	    .create(true)
+	    .truncate(false)
+	    .mode(0o600)
+	    .open(&self.state)
+	    .map_err(err)?;
+	// Held until the file is closed
+	if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+	    return Err(err(io::Error::last_os_error()));
+	}
+	let mut state = String::new();
+	file.read_to_string(&mut state).map_err(|_| Error::Corrupt(self.state.clone()))?;
+	let start = match state.trim() {
+	    // A new state file
+	    "" if state.is_empty() => 0,
+	    counter => counter.parse::<u64>().map_err(|_| Error::Corrupt(self.state.clone()))?,
+	};
+	// `u64::MAX` itself is never used, it marks the counter as exhausted
+	let end = start.checked_add(self.reserve).filter(|&end| end < u64::MAX).ok_or(Error::Exhausted)?;
+	// Always the same length, so it is overwritten in place without truncating the file first
+	file.write_all_at(format!("{:0width$}\n", end, width = STATE_DIGITS).as_bytes(), 0)
+	    .and_then(|_| file.sync_data())
+	    .map_err(err)?;
+	Ok(start..end)
+    }
+}
+
+/// An error generating a nonce
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error
+{
+    /// The state file could not be read or written
+    Io(PathBuf, io::Error),
+    /// The state file does not contain a counter
+    Corrupt(PathBuf),
+    /// The counter has run out, no more nonces can be generated
+    Exhausted,
+}
+
+impl error::Error for Error
+{
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+	match self {
+	    Self::Io(_, io) => Some(io),
+	    _ => None,
+	}
+    }
+}
+
+impl fmt::Display for Error
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+	match self {
+	    Self::Io(path, io) => write!(f, "nonce state `{}`: {}", path.display(), io),
+	    Self::Corrupt(path) => write!(f, "nonce state `{}` does not contain a counter, refusing to generate a nonce", path.display()),
+	    Self::Exhausted => f.write_str("the nonce counter has run out, refusing to generate a nonce (use a new key)"),
+	}
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn nonce_sequence()
+    {
+	let dir = TempDir::new("sequence");
+	let state = dir.join("state");
+	let mut sequence = NonceSequence::new(&state, cha::IV_SIZE);
+	let mut other = NonceSequence::new(&state, cha::IV_SIZE).with_reserve(10);
+	let nonces: Vec<_> = [sequence.next(), other.next(), sequence.next(), other.next()].into_iter().map(Result::unwrap).collect();
+	let counters: Vec<_> = nonces.iter().map(|n| u64::from_be_bytes(n.as_ref()[cha::IV_SIZE - COUNTER_SIZE..].try_into().unwrap())).collect();
+	// `other` reserved 1..11 when it was first used
+	assert_eq!(counters, [0, 1, 11, 2]);
+	assert_eq!(nonces[0].as_ref()[..4], nonces[2].as_ref()[..4]);
+	assert_eq!(fs::read_to_string(&state).unwrap().trim().parse::<u64>(), Ok(12));
+	assert!(matches!(NonceSequence::new(&state, cha::XNONCE_SIZE).next(), Ok(Nonce::XNonce(_))));
+
+	// It fails closed
+	fs::write(&state, format!("{}\n", u64::MAX - 1)).unwrap();
+	assert!(matches!(sequence.next(), Err(Error::Exhausted)));
+	fs::write(&state, "not a counter\n").unwrap();
+	assert!(matches!(sequence.next(), Err(Error::Corrupt(_))));
+    }
+}
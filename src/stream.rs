@@ -2,6 +2,7 @@
 
 use super::*;
 use key::*;
+use header::Header;
 
 use std::io::{self, Write};
 use std::fmt;
@@ -9,77 +10,169 @@ use openssl::{
     symm::Crypter,
     error::ErrorStack,
 };
-use smallvec::SmallVec;
+use secure::Buffer;
 
-pub const BUFFER_SIZE: usize = 32;
-pub type Error = ErrorStack;
+pub type Error = io::Error;
 
 /// ChaCha Sink
 ///
+/// # Authentication
+/// With `Framing::Single`:
+/// When encrypting, the Poly1305 tag is appended to the output by `finish()`.
+/// When decrypting, the last `TAG_SIZE` bytes written are held back as the tag, and are verified by `finish()`. Plaintext is written to the inner stream *before* it is verified.
+///
+/// With `Framing::Detached`:
+/// When encrypting, the tag is returned by `finish_with_tag()` instead of being written.
+/// When decrypting, the tag given with `Operation::with_tag()` is verified by `finish()`. Plaintext is written to the inner stream *before* it is verified.
+///
+/// With `Framing::Unauthenticated`:
+/// There is no tag, the input is just passed through plain ChaCha20 and `finish()` never fails authentication. **Nothing is verified.**
+///
+/// With `Framing::Chunked`:
+/// Input is buffered until a whole segment has been written, and each segment is written to the inner stream with its own tag.
+/// When decrypting, only verified plaintext is written to the inner stream.
+/// The last segment is written by `finish()`, `flush()` does not write partial segments.
+///
+/// Dropping the sink without calling `finish()` leaves the ciphertext untagged or unverified.
+///
+/// # Headers
+/// If the operation has a header, it is written before the ciphertext when encrypting.
+/// When decrypting, input is buffered until the whole header has been read, and the operation is then resolved with it.
+///
 /// # Note
 /// When writing, a temporary buffer stored in the structure is used. This buffer is **not** cleared after a write, for efficiency reasons. This may leave sensitive information in the buffer after the write operation.
 /// The `flush()` implementation *does* clear this buffer.
+/// This buffer (and the segment buffer) are locked into memory and excluded from core dumps, and are zeroed out when the sink is dropped (see `secure::Buffer`.)
 //#[derive(Debug)]
 pub struct Sink<W>
 {
     stream: W,
-    crypter: Crypter, // for chacha, finalize does nothing it seems. we can also call it multiple times.
+    op: cha::Operation,
+    crypter: Option<Crypter>, // `None` when chunked, each segment has its own crypter.
+    trailer: cha::TagTrailer, // holds back the tag when decrypting a single-tag ciphertext
 
-    buffer: SmallVec<[u8; BUFFER_SIZE]> // used to buffer the operation
+    segment: Buffer, // the segment being filled when chunked
+    index: u64, // the index of that segment
+    header: Option<Vec<u8>>, // the header still to be written when encrypting, or the part of it read so far when decrypting
+
+    buffer: Buffer // used to buffer the operation
 }
 
 impl<W: fmt::Debug> fmt::Debug for Sink<W>
 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
     {
-	write!(f, "Sink({:?}, ({} buffer cap))", self.stream, self.buffer.capacity())
+	write!(f, "Sink({:?}, {:?}, {:?}, ({} buffer cap))", self.stream, self.op.direction(), self.op.framing(), self.buffer.capacity())
     }
 }
 
 impl<W> Sink<W>
 where W: Write
 {
-    /// Create a new Chacha Sink stream wrapper
-    #[inline] fn new(stream: W, crypter: Crypter) -> Self
+    /// Create a new Chacha Sink stream wrapper for this operation
+    pub fn new(stream: W, op: cha::Operation) -> Result<Self, Error>
     {
-	Self{stream, crypter, buffer: SmallVec::new()}
+	let (op, header) = match (op.has_header(), op.direction()) {
+	    (false, _) => (op, None),
+	    (true, cha::Direction::Encrypt) => {
+		let (header, op) = op.seal_header()?;
+		(op, Some(header.to_bytes()?))
+	    },
+	    // The operation is started once the header has been read
+	    (true, cha::Direction::Decrypt) => (op, Some(Vec::new())),
+	};
+	let mut this = Self{stream, op: op.clone(), crypter: None, trailer: Default::default(), segment: Buffer::new(), index: 0, header, buffer: Buffer::new()};
+	if !op.has_header() {
+	    this.start(op)?;
+	}
+	Ok(this)
     }
 
-    /// Create an encrypting Chacha Sink stream wrapper
-    pub fn encrypt(stream: W, key: Key, iv: IV) -> Result<Self, Error>
+    /// Start processing the ciphertext with this (headerless) operation
+    fn start(&mut self, op: cha::Operation) -> Result<(), ErrorStack>
     {
-	Ok(Self::new(stream, cha::encrypter(key, iv)?))
+	self.crypter = match op.framing() {
+	    cha::Framing::Single | cha::Framing::Detached | cha::Framing::Unauthenticated(_) => Some(op.crypter()?),
+	    cha::Framing::Chunked(_) => None,
+	};
+	self.op = op;
+	Ok(())
+    }
+
+    /// Write the header when encrypting, or read it from `buf` when decrypting.
+    ///
+    /// Returns the rest of `buf` after the header, or `None` if all of `buf` was part of the header.
+    fn write_header<'a>(&mut self, buf: &'a [u8]) -> io::Result<Option<&'a [u8]>>
+    {
+	let Some(header) = self.header.as_mut() else {
+	    return Ok(Some(buf));
+	};
+	match self.op.direction() {
+	    cha::Direction::Encrypt => {
+		self.stream.write_all(&header[..])?;
+		self.header = None;
+		Ok(Some(buf))
+	    },
+	    cha::Direction::Decrypt => {
+		let read = header.len();
+		header.extend_from_slice(buf);
+		match Header::parse(&header[..])? {
+		    None => Ok(None),
+		    Some((header, len)) => {
+			let op = self.op.open_header(&header)?;
+			self.start(op)?;
+			self.header = None;
+			Ok(Some(&buf[(len - read)..]))
+		    },
+		}
+	    },
+	}
+    }
+
+    /// Create an encrypting Chacha Sink stream wrapper, authenticating `aad` as associated data
+    pub fn encrypt(stream: W, key: Key, iv: IV, aad: &[u8]) -> Result<Self, Error>
+    {
+	Self::new(stream, cha::Operation::new(cha::Direction::Encrypt, key, iv).with_aad(aad))
     }
     
-    /// Create a decrypting Chacha Sink stream wrapper
-    pub fn decrypt(stream: W, key: Key, iv: IV) -> Result<Self, Error>
+    /// Create a decrypting Chacha Sink stream wrapper, authenticating `aad` as associated data
+    pub fn decrypt(stream: W, key: Key, iv: IV, aad: &[u8]) -> Result<Self, Error>
     {
-	Ok(Self::new(stream, cha::decrypter(key, iv)?))
+	Self::new(stream, cha::Operation::new(cha::Direction::Decrypt, key, iv).with_aad(aad))
     }
     
 
     /// Consume into the inner stream
+    ///
+    /// # Note
+    /// This does not write or verify the authentication tag, use `finish()` for that.
     #[inline] pub fn into_inner(self) -> W
     {
 	self.stream
     }
 
-    /// Consume into the inner stream and crypter
-    #[inline] pub fn into_parts(self) -> (W, Crypter)
+    /// Consume into the inner stream and operation
+    #[inline] pub fn into_parts(self) -> (W, cha::Operation)
     {
-	(self.stream, self.crypter)
+	(self.stream, self.op)
     }
     
-    /// The crypter of this instance
-    #[inline] pub fn crypter(&self) -> &Crypter
+    /// The crypter of this instance, if the ciphertext is not chunked
+    #[inline] pub fn crypter(&self) -> Option<&Crypter>
     {
-	&self.crypter
+	self.crypter.as_ref()
     }
     
-    /// The crypter of this instance
-    #[inline] pub fn crypter_mut(&mut self) -> &mut Crypter
+    /// The crypter of this instance, if the ciphertext is not chunked
+    #[inline] pub fn crypter_mut(&mut self) -> Option<&mut Crypter>
+    {
+	self.crypter.as_mut()
+    }
+
+    /// The operation this instance performs
+    #[inline] pub fn operation(&self) -> &cha::Operation
     {
-	&mut self.crypter
+	&self.op
     }
 
     /// The inner stream
@@ -100,16 +193,50 @@ where W: Write
     pub fn prune(&mut self)
     {
 	#[cfg(feature="explicit_clear")]
-	{
-	    explicit_prune(&mut self.buffer[..]);
-	    return;
-	}
+	explicit_prune(&mut self.buffer[..]);
 	#[cfg(not(feature="explicit_clear"))] 
 	unsafe {
 	    std::ptr::write_bytes(self.buffer.as_mut_ptr(), 0, self.buffer.len());
 	}
     }
 
+    /// Finish the operation and consume into the inner stream.
+    ///
+    /// When encrypting, the authentication tag (or the last segment) is written to the stream.
+    /// When decrypting, the held-back tag (or the last segment) is checked; an `AuthenticationError` is returned (as `io::ErrorKind::InvalidData`) if it does not match.
+    #[inline] pub fn finish(self) -> io::Result<W>
+    {
+	self.finish_with_tag().map(|(stream, _)| stream)
+    }
+
+    /// As `finish()`, but also return the tag if this is a `Framing::Detached` encryption.
+    pub fn finish_with_tag(mut self) -> io::Result<(W, Option<cha::Tag>)>
+    {
+	if self.header.is_some() {
+	    match self.op.direction() {
+		cha::Direction::Encrypt => drop(self.write_header(&[])?),
+		cha::Direction::Decrypt => return Err(header::Error::Truncated.into()),
+	    }
+	}
+	let mut detached = None;
+	match (self.crypter.as_mut(), self.op.framing(), self.op.direction()) {
+	    (None, _, _) => self.write_segment(true)?,
+	    (Some(crypter), cha::Framing::Detached, cha::Direction::Encrypt) => detached = Some(cha::seal(crypter)?),
+	    (Some(crypter), cha::Framing::Detached, cha::Direction::Decrypt) => self.op.open_detached(crypter)?,
+	    (Some(_), cha::Framing::Unauthenticated(_), _) => (),
+	    (Some(crypter), _, cha::Direction::Encrypt) => {
+		let tag = cha::seal(crypter)?;
+		self.stream.write_all(&tag[..])?;
+	    },
+	    (Some(crypter), _, cha::Direction::Decrypt) => {
+		let tag = self.trailer.tag().ok_or(cha::AuthenticationError)?;
+		cha::open(crypter, &tag[..])?;
+	    },
+	}
+	self.flush()?;
+	Ok((self.stream, detached))
+    }
+
     /// Perform the cipher transform on this input to the inner buffer, returning the number of bytes updated.
     fn transform(&mut self, buf: &[u8]) -> Result<usize, ErrorStack>
     {
@@ -117,26 +244,72 @@ where W: Write
 	    self.buffer.resize(buf.len(), 0);
 	}
 	
-	let n = self.crypter.update(&buf[..], &mut self.buffer[..])?;
-	let _f = self.crypter.finalize(&mut self.buffer[..n])?; // I don't know if this is needed.
-	debug_assert_eq!(_f, 0);
+	self.crypter.as_mut().expect("transform() called on a chunked sink").update(buf, &mut self.buffer[..])
+    }
+
+    /// Transform this input and write all of it to the inner stream.
+    #[inline] fn transform_all(&mut self, buf: &[u8]) -> io::Result<()>
+    {
+	let n = self.transform(buf)?;
 
-	Ok(n)
+	self.stream.write_all(&self.buffer[..n])
+    }
+
+    /// Transform the current segment and write it to the inner stream.
+    fn write_segment(&mut self, last: bool) -> io::Result<()>
+    {
+	let size = self.segment.len() + cha::TAG_SIZE;
+	if size > self.buffer.len() {
+	    self.buffer.resize(size, 0);
+	}
+	let n = self.op.segment(self.index, last, &self.segment[..], &mut self.buffer[..])?;
+	self.index += 1;
+	self.segment.clear();
+	
+	self.stream.write_all(&self.buffer[..n])
+    }
+
+    /// Buffer this input into segments of `size`, writing each one once it is known not to be the last.
+    fn push_segments(&mut self, mut buf: &[u8], size: usize) -> io::Result<()>
+    {
+	// The segments of a ciphertext include their tags
+	let size = match self.op.direction() {
+	    cha::Direction::Encrypt => size,
+	    cha::Direction::Decrypt => size + cha::TAG_SIZE,
+	};
+	while !buf.is_empty() {
+	    if self.segment.len() == size {
+		// There is more input, so this segment is not the last.
+		self.write_segment(false)?;
+	    }
+	    let (fill, rest) = buf.split_at(std::cmp::min(size - self.segment.len(), buf.len()));
+	    self.segment.extend_from_slice(fill);
+	    buf = rest;
+	}
+	Ok(())
     }
 }
 
 impl<W: Write> Write for Sink<W>
 {
     #[inline] fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-	let n = self.transform(buf)?;
-
-	self.stream.write(&self.buffer[..n])
-	    
+	self.write_all(buf)?;
+	Ok(buf.len())
     }
     #[inline] fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
-	let n = self.transform(buf)?;
-
-	self.stream.write_all(&self.buffer[..n])
+	let Some(buf) = self.write_header(buf)? else {
+	    return Ok(());
+	};
+	match (self.op.framing(), self.op.direction()) {
+	    (cha::Framing::Chunked(size), _) => self.push_segments(buf, size),
+	    (cha::Framing::Single, cha::Direction::Encrypt) | (cha::Framing::Detached | cha::Framing::Unauthenticated(_), _) => self.transform_all(buf),
+	    (cha::Framing::Single, cha::Direction::Decrypt) => {
+		let mut trailer = self.trailer;
+		trailer.push(buf, |buf| self.transform_all(buf))?;
+		self.trailer = trailer;
+		Ok(())
+	    },
+	}
     }
     #[inline] fn flush(&mut self) -> io::Result<()> {
 	#[cfg(feature="explicit_clear")] self.prune();
@@ -152,22 +325,29 @@ mod tests
 {
     use super::*;
 
-    const INPUT: &'static str = "Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!";
+    const INPUT: &str = "Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!";
 
-    fn enc_stream(input: impl AsRef<[u8]>, key: Key, iv: IV) -> Sink<Vec<u8>>
+    fn enc_stream(input: impl AsRef<[u8]>, key: Key, iv: IV) -> Vec<u8>
     {
 	let enc_buffer = Vec::new();
 	let input = input.as_ref();
 	
 	eprintln!("(enc) Key: {}, IV: {}, Input: ({}, {})", key, iv, input.len(), input.hex());
 	
-	let mut stream = Sink::encrypt(enc_buffer, key, iv).expect("sink::enc");
+	let mut stream = Sink::encrypt(enc_buffer, key, iv, &[]).expect("sink::enc");
 	assert_eq!(stream.write(input).unwrap(), input.len());
 	stream.flush().unwrap();
 	
 	eprintln!("Output encrypted: {}", stream.inner().hex());
 
-	stream
+	stream.finish().expect("sink::enc finish")
+    }
+
+    fn dec_stream(input: impl AsRef<[u8]>, key: Key, iv: IV) -> io::Result<Vec<u8>>
+    {
+	let mut stream = Sink::decrypt(Vec::new(), key, iv, &[]).expect("sink::dec");
+	stream.write_all(input.as_ref())?;
+	stream.finish()
     }
 
     #[test]
@@ -185,20 +365,564 @@ mod tests
 	let (key, iv) = cha::keygen();
 	eprintln!("Input unencrypted: {}", INPUT.hex());
 
-	let input = enc_stream(INPUT.as_bytes(), key.clone(), iv.clone()).into_inner();
+	let input = enc_stream(INPUT.as_bytes(), key.clone(), iv);
+	assert_eq!(input.len(), INPUT.len() + cha::TAG_SIZE);
 
 	let mut dec_buffer = Vec::new();
 	{
-	    let mut stream = Sink::decrypt(&mut dec_buffer, key, iv).expect("sink::dec");
+	    let mut stream = Sink::decrypt(&mut dec_buffer, key, iv, &[]).expect("sink::dec");
 
-	    stream.write_all(&input[..]).unwrap();
+	    // Write in small pieces so the held-back tag straddles writes
+	    for chunk in input.chunks(7) {
+		stream.write_all(chunk).unwrap();
+	    }
 	    stream.flush().unwrap();
 	    
 	    eprintln!("Output decrypted: {}", stream.inner().hex());
+	    stream.finish().expect("sink::dec finish");
 	}
 	assert_eq!(&dec_buffer[..], INPUT.as_bytes());
     }
+
+    #[test]
+    fn tampered()
+    {
+	let (key, iv) = cha::keygen();
+	let input = enc_stream(INPUT.as_bytes(), key.clone(), iv);
+
+	let mut flipped = input.clone();
+	flipped[3] ^= 1;
+	let err = dec_stream(&flipped, key.clone(), iv).expect_err("tampered ciphertext was accepted");
+	assert!(cha::AuthenticationError::is_cause_of(&err));
+
+	let err = dec_stream(&input[..input.len()-1], key.clone(), iv).expect_err("truncated ciphertext was accepted");
+	assert!(cha::AuthenticationError::is_cause_of(&err));
+
+	let err = dec_stream(&input[..4], key, iv).expect_err("ciphertext shorter than the tag was accepted");
+	assert!(cha::AuthenticationError::is_cause_of(&err));
+    }
+
+    #[test]
+    fn aad()
+    {
+	const AAD: &[u8] = b"file.txt";
+	let (key, iv) = cha::keygen();
+
+	let mut stream = Sink::encrypt(Vec::new(), key.clone(), iv, AAD).expect("sink::enc");
+	stream.write_all(INPUT.as_bytes()).unwrap();
+	let input = stream.finish().unwrap();
+
+	let mut stream = Sink::decrypt(Vec::new(), key.clone(), iv, AAD).expect("sink::dec");
+	stream.write_all(&input[..]).unwrap();
+	assert_eq!(&stream.finish().expect("matching aad was rejected")[..], INPUT.as_bytes());
+
+	let mut stream = Sink::decrypt(Vec::new(), key.clone(), iv, b"other.txt").expect("sink::dec");
+	stream.write_all(&input[..]).unwrap();
+	assert!(cha::AuthenticationError::is_cause_of(&stream.finish().expect_err("mismatched aad was accepted")));
+	
+	let err = dec_stream(&input, key, iv).expect_err("missing aad was accepted");
+	assert!(cha::AuthenticationError::is_cause_of(&err));
+    }
+    
+    fn chunked(direction: cha::Direction, key: Key, iv: IV, size: usize) -> Sink<Vec<u8>>
+    {
+	Sink::new(Vec::new(), cha::Operation::new(direction, key, iv).with_framing(cha::Framing::Chunked(size))).expect("sink::chunked")
+    }
+
+    #[test]
+    fn chunked_roundtrip()
+    {
+	const SEGMENT: usize = 16;
+	let (key, iv) = cha::keygen();
+	for len in [0, 1, SEGMENT - 1, SEGMENT, SEGMENT + 1, SEGMENT * 3, INPUT.len()] {
+	    let input = &INPUT.as_bytes()[..len];
+
+	    let mut stream = chunked(cha::Direction::Encrypt, key.clone(), iv, SEGMENT);
+	    for chunk in input.chunks(5) {
+		stream.write_all(chunk).unwrap();
+	    }
+	    let enc = stream.finish().expect("sink::chunked finish");
+	    assert_eq!(Some(enc.len()), cha::Framing::Chunked(SEGMENT).output_size(cha::Direction::Encrypt, len));
+
+	    // The mapped path must produce the same ciphertext
+	    let op = cha::Operation::new(cha::Direction::Encrypt, key.clone(), iv).with_framing(cha::Framing::Chunked(SEGMENT));
+	    let mut mapped = vec![0u8; enc.len()];
+	    assert_eq!(op.process(input, &mut mapped[..]).unwrap(), enc.len());
+	    assert_eq!(mapped, enc);
+
+	    let mut stream = chunked(cha::Direction::Decrypt, key.clone(), iv, SEGMENT);
+	    for chunk in enc.chunks(7) {
+		stream.write_all(chunk).unwrap();
+	    }
+	    assert_eq!(&stream.finish().expect("sink::chunked dec finish")[..], input);
+	}
+    }
+
+    #[test]
+    fn chunked_tampered()
+    {
+	const SEGMENT: usize = 32;
+	const UNIT: usize = SEGMENT + cha::TAG_SIZE;
+	let (key, iv) = cha::keygen();
+
+	let mut stream = chunked(cha::Direction::Encrypt, key.clone(), iv, SEGMENT);
+	stream.write_all(INPUT.as_bytes()).unwrap();
+	let enc = stream.finish().unwrap();
+	assert!(enc.len() > UNIT * 3);
+
+	let dec = |input: &[u8]| {
+	    let mut stream = chunked(cha::Direction::Decrypt, key.clone(), iv, SEGMENT);
+	    stream.write_all(input).and_then(|_| stream.finish())
+	};
+	let assert_rejected = |input: &[u8], what: &str| {
+	    let err = dec(input).expect_err(what);
+	    assert!(cha::AuthenticationError::is_cause_of(&err), "{}: {}", what, err);
+	};
+
+	// Truncated at a segment boundary
+	assert_rejected(&enc[..UNIT * 2], "truncated ciphertext was accepted");
+	// Segments reordered
+	let mut reordered = enc.clone();
+	reordered[..UNIT].copy_from_slice(&enc[UNIT..UNIT*2]);
+	reordered[UNIT..UNIT*2].copy_from_slice(&enc[..UNIT]);
+	assert_rejected(&reordered, "reordered ciphertext was accepted");
+	// Segment spliced in from another ciphertext under the same key
+	let mut stream = chunked(cha::Direction::Encrypt, key.clone(), cha::keygen().1, SEGMENT);
+	stream.write_all(INPUT.as_bytes()).unwrap();
+	let other = stream.finish().unwrap();
+	let mut spliced = enc.clone();
+	spliced[UNIT..UNIT*2].copy_from_slice(&other[UNIT..UNIT*2]);
+	assert_rejected(&spliced, "spliced ciphertext was accepted");
+
+	// Verified segments before the bad one are still released, but nothing after it
+	let mut flipped = enc.clone();
+	flipped[UNIT + 1] ^= 1;
+	let mut stream = chunked(cha::Direction::Decrypt, key, iv, SEGMENT);
+	assert!(stream.write_all(&flipped).is_err());
+	assert_eq!(&stream.inner()[..], &INPUT.as_bytes()[..SEGMENT]);
+    }
     
+    #[test]
+    fn header()
+    {
+	let (key, iv) = cha::keygen();
+	let op = |direction, iv| cha::Operation::new(direction, key.clone(), iv).with_header(true).with_aad(&b"aad"[..]);
+	for framing in [cha::Framing::Single, cha::Framing::Chunked(16)] {
+	    let mut stream = Sink::new(Vec::new(), op(cha::Direction::Encrypt, Some(iv)).with_framing(framing)).unwrap();
+	    stream.write_all(INPUT.as_bytes()).unwrap();
+	    let enc = stream.finish().unwrap();
+	    assert!(enc.starts_with(&header::MAGIC[..]));
+
+	    // The mapped path must produce the same ciphertext
+	    let enc_op = op(cha::Direction::Encrypt, Some(iv)).with_framing(framing);
+	    let mut mapped = vec![0u8; enc_op.output_size(INPUT.len()).unwrap()];
+	    enc_op.process(INPUT.as_bytes(), &mut mapped[..]).unwrap();
+	    assert_eq!(mapped, enc);
+	    
+	    // Only the key is needed to decrypt, the framing and IV are read from the header
+	    let dec = |input: &[u8], iv| {
+		let mut stream = Sink::new(Vec::new(), op(cha::Direction::Decrypt, iv))?;
+		for chunk in input.chunks(3) {
+		    stream.write_all(chunk)?;
+		}
+		stream.finish()
+	    };
+	    assert_eq!(&dec(&enc, None).unwrap()[..], INPUT.as_bytes());
+	    assert_eq!(&dec(&enc, Some(iv)).unwrap()[..], INPUT.as_bytes());
+	    let dec_op = op(cha::Direction::Decrypt, None);
+	    let mut mapped = vec![0u8; dec_op.output_size_of(&enc[..]).unwrap()];
+	    dec_op.process(&enc[..], &mut mapped[..]).unwrap();
+	    assert_eq!(&mapped[..], INPUT.as_bytes());
+
+	    dec(&enc, Some(cha::keygen().1)).expect_err("mismatched iv was accepted");
+	    dec(&enc[..10], None).expect_err("truncated header was accepted");
+	    dec(INPUT.as_bytes(), None).expect_err("input without a header was accepted");
+
+	    // The header is authenticated
+	    let mut flipped = enc.clone();
+	    flipped[10] ^= 1;
+	    let err = dec(&flipped, None).expect_err("tampered header was accepted");
+	    assert!(cha::AuthenticationError::is_cause_of(&err));
+	}
+    }
+
+    #[test]
+    fn passphrase()
+    {
+	let op = |direction, passphrase: &[u8], kdf, nonce| cha::Operation::with_suite(direction, cha::CipherSuite::default(), cha::KeySource::Passphrase(passphrase.to_vec().into(), kdf), nonce).with_header(true);
+	let dec = |input: &[u8], passphrase: &[u8]| {
+	    let mut stream = Sink::new(Vec::new(), op(cha::Direction::Decrypt, passphrase, None, None))?;
+	    stream.write_all(input)?;
+	    stream.finish()
+	};
+	for kdf in [kdf::Kdf::scrypt(10, 8, 1), kdf::Kdf::argon2id(256, 2, 2)] {
+	    let mut stream = Sink::new(Vec::new(), op(cha::Direction::Encrypt, b"hunter2", Some(kdf), Some(cha::CipherSuite::default().new_nonce()))).unwrap();
+	    stream.write_all(INPUT.as_bytes()).unwrap();
+	    let enc = stream.finish().unwrap();
+
+	    // The KDF is read from the header, so only the passphrase is needed to decrypt
+	    let (header, _) = header::Header::parse(&enc[..]).unwrap().unwrap();
+	    assert_eq!(header.kdf(), Ok(Some(kdf)));
+	    assert_eq!(&dec(&enc, b"hunter2").unwrap()[..], INPUT.as_bytes());
+	    let err = dec(&enc, b"hunter3").expect_err("wrong passphrase was accepted");
+	    assert!(cha::AuthenticationError::is_cause_of(&err));
+	}
+
+	// A ciphertext encrypted with a key cannot be decrypted with a passphrase
+	let (key, iv) = cha::keygen();
+	let mut stream = Sink::new(Vec::new(), cha::Operation::new(cha::Direction::Encrypt, key, iv).with_header(true)).unwrap();
+	stream.write_all(INPUT.as_bytes()).unwrap();
+	let enc = stream.finish().unwrap();
+	dec(&enc, b"hunter2").expect_err("ciphertext without a KDF was decrypted with a passphrase");
+    }
+
+    #[test]
+    fn recipient()
+    {
+	let identity = recipient::Identity::new();
+	let public = identity.public_key().unwrap();
+	assert_eq!(public.to_string().parse::<recipient::PublicKey>(), Ok(public));
+	assert_eq!(recipient::Identity::decode(identity.expose_secret().encode(Encoding::Hex).as_bytes()).as_ref(), Ok(&identity));
+
+	let dec = |input: &[u8], identity: &recipient::Identity| {
+	    let op = cha::Operation::with_suite(cha::Direction::Decrypt, cha::CipherSuite::default(), cha::KeySource::Identity(identity.clone()), None).with_header(true);
+	    let mut stream = Sink::new(Vec::new(), op)?;
+	    stream.write_all(input)?;
+	    stream.finish()
+	};
+	let header_error = |err: io::Error| err.get_ref().and_then(|e| e.downcast_ref::<header::Error>()).cloned();
+
+	for suite in cha::CipherSuite::ALL {
+	    let op = cha::Operation::with_suite(cha::Direction::Encrypt, suite, cha::KeySource::Recipient(public), None).with_header(true);
+	    let mut stream = Sink::new(Vec::new(), op).unwrap();
+	    stream.write_all(INPUT.as_bytes()).unwrap();
+	    let enc = stream.finish().unwrap();
+
+	    let (header, _) = header::Header::parse(&enc[..]).unwrap().unwrap();
+	    assert_eq!(header.recipients().unwrap().len(), 1);
+	    assert_eq!(&dec(&enc, &identity).unwrap()[..], INPUT.as_bytes());
+
+	    // Another identity cannot unwrap the file key, so it is rejected before decrypting anything
+	    let err = dec(&enc, &recipient::Identity::new()).expect_err("wrong identity was accepted");
+	    assert_eq!(header_error(err), Some(header::Error::NotRecipient));
+	}
+
+	// A ciphertext encrypted with a key has no recipient
+	let (key, iv) = cha::keygen();
+	let mut stream = Sink::new(Vec::new(), cha::Operation::new(cha::Direction::Encrypt, key, iv).with_header(true)).unwrap();
+	stream.write_all(INPUT.as_bytes()).unwrap();
+	let enc = stream.finish().unwrap();
+	assert_eq!(dec(&enc, &identity).map_err(header_error).err(), Some(Some(header::Error::MissingRecipient)));
+    }
+
+    #[test]
+    fn recipients()
+    {
+	let identity = recipient::Identity::new();
+	let (key, _) = cha::keygen();
+	let passphrase = || cha::KeySource::Passphrase(b"hunter2".to_vec().into(), Some(kdf::Kdf::argon2id(256, 1, 1)));
+	let recipients = vec![cha::KeySource::Recipient(identity.public_key().unwrap()), key.clone().into(), passphrase()];
+
+	let op = |direction, key| cha::Operation::with_suite(direction, cha::CipherSuite::default(), key, None).with_header(true).with_framing(cha::Framing::Chunked(16));
+	let mut stream = Sink::new(Vec::new(), op(cha::Direction::Encrypt, cha::KeySource::Recipients(recipients.clone()))).unwrap();
+	stream.write_all(INPUT.as_bytes()).unwrap();
+	let enc = stream.finish().unwrap();
+
+	// The file key is wrapped for each recipient in the unbound sections (followed by the reserved padding,) which are sized without wrapping it
+	let (header, len) = header::Header::parse(&enc[..]).unwrap().unwrap();
+	let stanzas = header.recipients().unwrap();
+	assert_eq!(stanzas.iter().map(|s| s.to_bytes().len()).collect::<Vec<_>>(), recipients.iter().map(recipient::Stanza::size_for).collect::<Vec<_>>());
+	assert_eq!(header.aad().unwrap().len(), len - stanzas.iter().map(|s| 3 + s.to_bytes().len()).sum::<usize>() - (3 + usize::from(header::RESERVED_SIZE)));
+	assert_eq!(op(cha::Direction::Encrypt, cha::KeySource::Recipients(recipients)).output_size(INPUT.len()), Some(enc.len()));
+
+	let dec = |key| -> io::Result<Vec<u8>> {
+	    let mut stream = Sink::new(Vec::new(), op(cha::Direction::Decrypt, key))?;
+	    stream.write_all(&enc[..])?;
+	    stream.finish()
+	};
+	let other = recipient::Identity::new();
+	for key in [cha::KeySource::Identity(identity), key.clone().into(), passphrase(), cha::KeySource::Recipients(vec![cha::KeySource::Identity(other.clone()), key.into()])] {
+	    assert_eq!(&dec(key).unwrap()[..], INPUT.as_bytes());
+	}
+	dec(cha::KeySource::Identity(other)).expect_err("ciphertext was decrypted by a key it was not wrapped for");
+
+	// A stanza that has been tampered with does not unwrap
+	let (key, _) = cha::keygen();
+	let stanza = recipient::wrap(&key.clone().into(), &cha::keygen().0).unwrap();
+	assert!(recipient::unwrap(&key.clone().into(), &stanza).unwrap().is_some());
+	let mut tampered = stanza;
+	tampered.wrapped[0] ^= 1;
+	assert_eq!(recipient::unwrap(&key.into(), &tampered), Ok(None));
+	assert_eq!(recipient::Stanza::from_bytes(&stanza.to_bytes()[..]), Ok(stanza));
+    }
+
+    #[test]
+    fn rewrap()
+    {
+	let (identity, other) = (recipient::Identity::new(), recipient::Identity::new());
+	let (key, _) = cha::keygen();
+	let op = |direction, key| cha::Operation::with_suite(direction, cha::CipherSuite::default(), key, None).with_header(true).with_framing(cha::Framing::Chunked(16));
+	let mut stream = Sink::new(Vec::new(), op(cha::Direction::Encrypt, cha::KeySource::Recipients(vec![cha::KeySource::Recipient(identity.public_key().unwrap())]))).unwrap();
+	stream.write_all(INPUT.as_bytes()).unwrap();
+	let enc = stream.finish().unwrap();
+	let dec = |enc: &[u8], key| -> io::Result<Vec<u8>> {
+	    let mut stream = Sink::new(Vec::new(), op(cha::Direction::Decrypt, key))?;
+	    stream.write_all(enc)?;
+	    stream.finish()
+	};
+	// Replace the header in place, leaving the ciphertext after it as it is
+	let rewrap = |enc: &[u8], changes: &recipient::Rewrap, key: cha::KeySource| -> Result<Vec<u8>, header::Error> {
+	    let header = header::rewrite(enc, |header| changes.apply(header, key.sources()))?;
+	    assert_eq!(header::Header::parse(&header[..]).unwrap().unwrap().1, header.len());
+	    Ok([&header[..], &enc[header.len()..]].concat())
+	};
+
+	let add = recipient::Rewrap {
+	    add: vec![cha::KeySource::Recipient(other.public_key().unwrap()), key.clone().into()],
+	    ..Default::default()
+	};
+	let added = rewrap(&enc[..], &add, cha::KeySource::Identity(identity.clone())).unwrap();
+	assert_eq!(added.len(), enc.len());
+	for key in [cha::KeySource::Identity(identity.clone()), cha::KeySource::Identity(other.clone()), key.clone().into()] {
+	    assert_eq!(&dec(&added[..], key).unwrap()[..], INPUT.as_bytes());
+	}
+	rewrap(&enc[..], &add, cha::KeySource::Identity(other.clone())).expect_err("file key was unwrapped by an identity it was not wrapped for");
+
+	// Public keys are removed by their hint, without needing the identity
+	let remove = recipient::Rewrap {
+	    remove: vec![cha::KeySource::Recipient(identity.public_key().unwrap())],
+	    ..Default::default()
+	};
+	let removed = rewrap(&added[..], &remove, key.clone().into()).unwrap();
+	assert_eq!(header::Header::parse(&removed[..]).unwrap().unwrap().0.recipients().unwrap().len(), 2);
+	dec(&removed[..], cha::KeySource::Identity(identity.clone())).expect_err("ciphertext was decrypted by a removed recipient");
+	assert_eq!(&dec(&removed[..], cha::KeySource::Identity(other.clone())).unwrap()[..], INPUT.as_bytes());
+
+	let remove = recipient::Rewrap {
+	    remove: vec![cha::KeySource::Recipient(other.public_key().unwrap()), key.clone().into()],
+	    ..Default::default()
+	};
+	assert_eq!(rewrap(&removed[..], &remove, key.clone().into()), Err(header::Error::NoRecipients));
+	let full = recipient::Rewrap {
+	    add: (0..16).map(|_| cha::KeySource::Recipient(recipient::Identity::new().public_key().unwrap())).collect(),
+	    ..Default::default()
+	};
+	assert_eq!(rewrap(&enc[..], &full, cha::KeySource::Identity(identity.clone())), Err(header::Error::NoRoom));
+	// Only the unbound sections can be rewritten
+	assert_eq!(header::rewrite(&enc[..], |header| Ok(header::Header { nonce: header.suite.new_nonce(), ..header.clone() })), Err(header::Error::BoundSectionsChanged));
+
+	// A ciphertext encrypted directly with a key has no file key to rewrap
+	let mut stream = Sink::new(Vec::new(), cha::Operation::with_suite(cha::Direction::Encrypt, cha::CipherSuite::default(), cha::KeySource::from(key.clone()), Some(cha::CipherSuite::default().new_nonce())).with_header(true)).unwrap();
+	stream.write_all(INPUT.as_bytes()).unwrap();
+	let direct = stream.finish().unwrap();
+	assert_eq!(rewrap(&direct[..], &add, key.into()), Err(header::Error::NoFileKey));
+    }
+
+    #[test]
+    fn detached()
+    {
+	let (key, iv) = cha::keygen();
+	let op = |direction| cha::Operation::new(direction, key.clone(), iv).with_framing(cha::Framing::Detached);
+
+	let mut stream = Sink::new(Vec::new(), op(cha::Direction::Encrypt)).unwrap();
+	stream.write_all(INPUT.as_bytes()).unwrap();
+	let (enc, tag) = stream.finish_with_tag().unwrap();
+	let tag = tag.expect("detached encryption returned no tag");
+	assert_eq!(enc.len(), INPUT.len());
+
+	// The tag is what would have been appended
+	let attached = enc_stream(INPUT, key.clone(), iv);
+	assert_eq!(&attached[..INPUT.len()], &enc[..]);
+	assert_eq!(&attached[INPUT.len()..], &tag[..]);
+
+	let mut mapped = vec![0u8; INPUT.len()];
+	assert_eq!(op(cha::Direction::Encrypt).process_with_tag(INPUT.as_bytes(), &mut mapped[..]).unwrap(), (INPUT.len(), Some(tag)));
+	assert_eq!(mapped, enc);
+
+	let dec = |op: cha::Operation| {
+	    let mut stream = Sink::new(Vec::new(), op)?;
+	    stream.write_all(&enc[..])?;
+	    stream.finish()
+	};
+	assert_eq!(&dec(op(cha::Direction::Decrypt).with_tag(tag)).unwrap()[..], INPUT.as_bytes());
+	let err = dec(op(cha::Direction::Decrypt)).expect_err("missing tag was accepted");
+	assert!(cha::AuthenticationError::is_cause_of(&err));
+	let mut bad = tag;
+	bad[0] ^= 1;
+	let err = dec(op(cha::Direction::Decrypt).with_tag(bad)).expect_err("wrong tag was accepted");
+	assert!(cha::AuthenticationError::is_cause_of(&err));
+	op(cha::Direction::Decrypt).with_tag(bad).process(&enc[..], &mut mapped[..]).expect_err("wrong tag was accepted");
+
+	// With a header, the framing is recorded in it
+	let mut stream = Sink::new(Vec::new(), op(cha::Direction::Encrypt).with_header(true)).unwrap();
+	stream.write_all(INPUT.as_bytes()).unwrap();
+	let (enc, tag) = stream.finish_with_tag().unwrap();
+	let mut stream = Sink::new(Vec::new(), cha::Operation::new(cha::Direction::Decrypt, key, None).with_header(true).with_tag(tag.unwrap())).unwrap();
+	stream.write_all(&enc[..]).unwrap();
+	assert_eq!(&stream.finish().unwrap()[..], INPUT.as_bytes());
+    }
+
+    #[test]
+    fn verify()
+    {
+	let (key, iv) = cha::keygen();
+	let input = INPUT.repeat(1000);
+	for framing in [cha::Framing::Single, cha::Framing::Chunked(100), cha::Framing::Detached] {
+	    let op = |direction| cha::Operation::new(direction, key.clone(), iv).with_header(true).with_framing(framing);
+	    let enc_op = op(cha::Direction::Encrypt);
+	    let mut enc = vec![0u8; enc_op.output_size(input.len()).unwrap()];
+	    let (_, tag) = enc_op.process_with_tag(input.as_bytes(), &mut enc[..]).unwrap();
+	    let dec_op = match tag {
+		Some(tag) => op(cha::Direction::Decrypt).with_tag(tag),
+		None => op(cha::Direction::Decrypt),
+	    };
+	    dec_op.verify(&enc[..]).expect("verify");
+
+	    let last = enc.len() - 1;
+	    enc[last] ^= 1;
+	    let err = dec_op.verify(&enc[..]).expect_err("tampered ciphertext was verified");
+	    assert!(cha::AuthenticationError::is_cause_of(&err));
+	    enc[last] ^= 1;
+	    let err = dec_op.verify(&enc[..(enc.len() - 100)]).expect_err("truncated ciphertext was verified");
+	    assert!(cha::AuthenticationError::is_cause_of(&err));
+	}
+    }
+
+    /// Test vectors from draft-irtf-cfrg-xchacha-03
+    #[test]
+    fn xchacha()
+    {
+	let key = Key::from_bytes(decode_hex("000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f").unwrap().try_into().unwrap());
+	let subkey = cha::hchacha20(&key, &decode_hex("000000090000004a0000000031415927").unwrap().try_into().unwrap()).unwrap();
+	assert_eq!(&subkey.expose_secret()[..], &decode_hex("82413b4227b27bfed30e42508a877d73a0f9e4d58a74a853c12ec41326d3ecdc").unwrap()[..]);
+
+	const PLAINTEXT: &str = "Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.";
+	let key = Key::from_bytes(decode_hex("808182838485868788898a8b8c8d8e8f909192939495969798999a9b9c9d9e9f").unwrap().try_into().unwrap());
+	let nonce = XNonce::from_bytes(decode_hex("404142434445464748494a4b4c4d4e4f5051525354555657").unwrap().try_into().unwrap());
+	let aad = decode_hex("50515253c0c1c2c3c4c5c6c7").unwrap();
+	let expected = decode_hex("bd6d179d3e83d43b9576579493c0e939572a1700252bfaccbed2902c21396cbb731c7f1b0b4aa6440bf3a82f4eda7e39ae64c6708c54c216cb96b72e1213b4522f8c9ba40db5d945b11b69b982c1bb9e3f3fac2bc369488f76b2383565d3fff921f9664c97637da9768812f615c68b13b52ec0875924c1c7987947deafd8780acf49").unwrap();
+
+	let mut stream = Sink::new(Vec::new(), cha::Operation::extended(cha::Direction::Encrypt, key.clone(), nonce).with_aad(&aad[..])).unwrap();
+	stream.write_all(PLAINTEXT.as_bytes()).unwrap();
+	assert_eq!(stream.finish().unwrap(), expected);
+
+	let mut stream = Sink::new(Vec::new(), cha::Operation::extended(cha::Direction::Decrypt, key.clone(), nonce).with_aad(&aad[..])).unwrap();
+	stream.write_all(&expected[..]).unwrap();
+	assert_eq!(&stream.finish().unwrap()[..], PLAINTEXT.as_bytes());
+
+	// The algorithm and nonce are read from the header
+	let op = cha::Operation::extended(cha::Direction::Encrypt, key.clone(), nonce).with_header(true).with_framing(cha::Framing::Chunked(16));
+	let mut enc = vec![0u8; op.output_size(PLAINTEXT.len()).unwrap()];
+	op.process(PLAINTEXT.as_bytes(), &mut enc[..]).unwrap();
+	let op = cha::Operation::new(cha::Direction::Decrypt, key.clone(), None).with_header(true);
+	let mut dec = vec![0u8; op.output_size_of(&enc[..]).unwrap()];
+	op.process(&enc[..], &mut dec[..]).unwrap();
+	assert_eq!(&dec[..], PLAINTEXT.as_bytes());
+	cha::Operation::extended(cha::Direction::Decrypt, key.clone(), XNonce::new()).with_header(true).process(&enc[..], &mut dec[..]).expect_err("mismatched nonce was accepted");
+	cha::Operation::new(cha::Direction::Decrypt, key, cha::keygen().1).with_header(true).process(&enc[..], &mut dec[..]).expect_err("mismatched algorithm was accepted");
+    }
+
+    /// Test case 16 from the GCM specification, and a roundtrip through every suite
+    #[test]
+    fn suites()
+    {
+	let key = Key::from_bytes(decode_hex("feffe9928665731c6d6a8f9467308308feffe9928665731c6d6a8f9467308308").unwrap().try_into().unwrap());
+	let iv = IV::from_bytes(decode_hex("cafebabefacedbaddecaf888").unwrap().try_into().unwrap());
+	let plaintext = decode_hex("d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a721c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b39").unwrap();
+	let aad = decode_hex("feedfacedeadbeeffeedfacedeadbeefabaddad2").unwrap();
+	let expected = decode_hex("522dc1f099567d07f47f37a32a84427d643a8cdcbfe5c0c97598a2bd2555d1aa8cb08e48590dbb3da7b08b1056828838c5f61e6393ba7a0abcc9f66276fc6ece0f4e1768cddf8853bb2d551b").unwrap();
+	let op = |direction| cha::Operation::with_suite(direction, cha::CipherSuite::Aes256Gcm, key.clone(), Some(iv.into())).with_aad(&aad[..]);
+
+	let mut stream = Sink::new(Vec::new(), op(cha::Direction::Encrypt)).unwrap();
+	stream.write_all(&plaintext[..]).unwrap();
+	assert_eq!(stream.finish().unwrap(), expected);
+	let mut stream = Sink::new(Vec::new(), op(cha::Direction::Decrypt)).unwrap();
+	stream.write_all(&expected[..]).unwrap();
+	assert_eq!(stream.finish().unwrap(), plaintext);
+
+	for suite in cha::CipherSuite::ALL {
+	    assert_eq!(cha::CipherSuite::from_name(suite.name()), Some(suite));
+	    for framing in [cha::Framing::Single, cha::Framing::Chunked(10)] {
+		let enc_op = cha::Operation::with_suite(cha::Direction::Encrypt, suite, key.clone(), Some(suite.new_nonce())).with_header(true).with_framing(framing);
+		let mut enc = vec![0u8; enc_op.output_size(INPUT.len()).unwrap()];
+		enc_op.process(INPUT.as_bytes(), &mut enc[..]).unwrap();
+
+		// The suite is read from the header
+		let dec_op = cha::Operation::new(cha::Direction::Decrypt, key.clone(), None).with_header(true);
+		let mut stream = Sink::new(Vec::new(), dec_op.clone()).unwrap();
+		stream.write_all(&enc[..]).unwrap();
+		assert_eq!(&stream.finish().unwrap()[..], INPUT.as_bytes());
+
+		let last = enc.len() - 1;
+		enc[last] ^= 1;
+		let err = dec_op.verify(&enc[..]).expect_err("tampered ciphertext was verified");
+		assert!(cha::AuthenticationError::is_cause_of(&err));
+	    }
+	}
+    }
+
+    /// Test vector from RFC 8439 section 2.4.2
+    #[test]
+    fn unauthenticated()
+    {
+	const PLAINTEXT: &str = "Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.";
+	let key = Key::from_bytes(decode_hex("000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f").unwrap().try_into().unwrap());
+	let iv = IV::from_bytes(decode_hex("000000000000004a00000000").unwrap().try_into().unwrap());
+	let expected = decode_hex("6e2e359a2568f98041ba0728dd0d6981e97e7aec1d4360c20a27afccfd9fae0bf91b65c5524733ab8f593dabcd62b3571639d624e65152ab8f530c359f0861d807ca0dbf500d6a6156a38e088a22b65e52bc514d16ccf806818ce91ab77937365af90bbf74a35be6b40b8eedf2785e42874d").unwrap();
+	let op = |direction| cha::Operation::new(direction, key.clone(), iv).with_framing(cha::Framing::Unauthenticated(1));
+
+	let mut stream = Sink::new(Vec::new(), op(cha::Direction::Encrypt)).unwrap();
+	stream.write_all(PLAINTEXT.as_bytes()).unwrap();
+	assert_eq!(stream.finish().unwrap(), expected);
+
+	let mut dec = vec![0u8; expected.len()];
+	assert_eq!(op(cha::Direction::Decrypt).process(&expected[..], &mut dec[..]).unwrap(), PLAINTEXT.len());
+	assert_eq!(&dec[..], PLAINTEXT.as_bytes());
+
+	// Starting at a later block is the same as skipping that part of the keystream
+	let mut later = vec![0u8; expected.len() - 64];
+	cha::Operation::new(cha::Direction::Decrypt, key.clone(), iv).with_framing(cha::Framing::Unauthenticated(2)).process(&expected[64..], &mut later[..]).unwrap();
+	assert_eq!(&later[..], &PLAINTEXT.as_bytes()[64..]);
+
+	op(cha::Direction::Encrypt).with_header(true).seal_header().expect_err("unauthenticated ciphertext was given a header");
+	op(cha::Direction::Decrypt).verify(&expected[..]).expect_err("unauthenticated ciphertext was verified");
+    }
+
+    #[test]
+    fn range()
+    {
+	let (key, iv) = cha::keygen();
+	let input = INPUT.repeat(100);
+	for framing in [cha::Framing::Single, cha::Framing::Chunked(100), cha::Framing::Detached, cha::Framing::Unauthenticated(3)] {
+	    let op = |direction| cha::Operation::new(direction, key.clone(), iv).with_header(framing != cha::Framing::Unauthenticated(3)).with_framing(framing);
+	    let enc_op = op(cha::Direction::Encrypt);
+	    let mut enc = vec![0u8; enc_op.output_size(input.len()).unwrap()];
+	    let (_, tag) = enc_op.process_with_tag(input.as_bytes(), &mut enc[..]).unwrap();
+	    let dec_op = match tag {
+		Some(tag) => op(cha::Direction::Decrypt).with_tag(tag),
+		None => op(cha::Direction::Decrypt),
+	    };
+	    for (offset, length) in [(0, Some(0)), (0, None), (1, Some(1)), (99, Some(2)), (150, Some(1000)), (input.len() - 10, Some(100)), (input.len() + 10, None)] {
+		let mut output = Vec::new();
+		let n = dec_op.process_range(&enc[..], offset, length, &mut output).unwrap();
+		let start = std::cmp::min(offset, input.len());
+		let expected = &input.as_bytes()[start..length.map(|l| std::cmp::min(start + l, input.len())).unwrap_or(input.len())];
+		assert_eq!(n, expected.len());
+		assert_eq!(&output[..], expected, "{:?} {}..+{:?}", framing, offset, length);
+	    }
+	}
+
+	// Only the touched segments are authenticated
+	let op = |direction| cha::Operation::new(direction, key.clone(), iv).with_framing(cha::Framing::Chunked(100));
+	let mut enc = vec![0u8; op(cha::Direction::Encrypt).output_size(input.len()).unwrap()];
+	op(cha::Direction::Encrypt).process(input.as_bytes(), &mut enc[..]).unwrap();
+	enc[300] ^= 1;
+	let mut output = Vec::new();
+	op(cha::Direction::Decrypt).process_range(&enc[..], 0, Some(100), &mut output).unwrap();
+	let err = op(cha::Direction::Decrypt).process_range(&enc[..], 250, Some(100), &mut output).expect_err("tampered segment was accepted");
+	assert!(cha::AuthenticationError::is_cause_of(&err));
+    }
+
     /// Checks if explicit clear is actually clearing.
     #[cfg(feature="explicit_clear")] 
     #[test]
@@ -209,10 +933,10 @@ mod tests
 	let (buf, off, _s) = {
 	    let (key, iv) = cha::keygen();
 
-	    let input = enc_stream(INPUT.as_bytes(), key.clone(), iv.clone()).into_inner();
+	    let input = enc_stream(INPUT.as_bytes(), key.clone(), iv);
 
 	    {
-		let mut stream = Sink::decrypt(&mut dec_buffer, key, iv).expect("sink::rem");
+		let mut stream = Sink::decrypt(&mut dec_buffer, key, iv, &[]).expect("sink::rem");
 
 		stream.write_all(&input[..]).unwrap();
 
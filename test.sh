@@ -45,7 +45,20 @@ else
 	exit 3
 fi
 
-rm test.*
+echo ">>> Adding a key to a keyring from an fd"
+$PROG keygen --out test.key                                       || exit 4
+$PROG keys add test --keyring test.keyring --key-fd 3 3< test.key || exit 5
+echo ">>> Encrypting with the keyring, decrypting with the key file"
+$PROG e --keyring test.keyring --key-id test < test.txt > test.cc20 || exit 6
+$PROG d --key-file test.key < test.cc20 > test.out.txt              || exit 7
+if cmp --silent -- test.txt test.out.txt; then
+	echo "Pass!"
+else
+	echo "Failed"
+	exit 8
+fi
+
+rm -r test.*
 
 cd ..
 rmdir test || exit -1